@@ -0,0 +1,294 @@
+//! Headless clients for load testing a server
+//!
+//! Connects a number of simulated players that fly in circles, periodically logging connection
+//! failures, disconnects, and the volume of data the server sends each tick.
+
+mod stats;
+
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Context, Error, Result};
+use futures::{future, pin_mut, select, FutureExt, StreamExt, TryStreamExt};
+use tokio::sync::mpsc;
+
+use common::{
+    codec,
+    graph::NodeId,
+    proto::{self, ClientMessage, Command, MovementMode},
+    session::{self, Disconnect, Session},
+    EntityId,
+};
+use stats::{Event, MessageKind, Stats};
+
+struct Config {
+    server: String,
+    server_name: String,
+    players: u32,
+    /// Commands sent per second by each player, defaulting to the server's tick rate
+    rate: Option<f64>,
+    /// How long to run for, or until interrupted if `None`
+    duration: Option<Duration>,
+}
+
+impl Config {
+    fn from_args() -> Result<Self> {
+        let mut cfg = Config {
+            server: "localhost:1234".into(),
+            server_name: "localhost".into(),
+            players: 1,
+            rate: None,
+            duration: None,
+        };
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| anyhow!("{} requires a value", arg))
+            };
+            match &arg[..] {
+                "--server" => cfg.server = value()?,
+                "--server-name" => cfg.server_name = value()?,
+                "--players" => cfg.players = value()?.parse().context("parsing --players")?,
+                "--rate" => cfg.rate = Some(value()?.parse().context("parsing --rate")?),
+                "--duration" => {
+                    let secs = value()?.parse().context("parsing --duration")?;
+                    cfg.duration = Some(Duration::from_secs_f64(secs));
+                }
+                _ => bail!(
+                    "unknown argument {:?}\n\
+                     usage: bot [--server ADDRESS] [--server-name NAME] [--players N] \
+                     [--rate COMMANDS_PER_SECOND] [--duration SECONDS]",
+                    arg
+                ),
+            }
+        }
+        Ok(cfg)
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    // Set up logging
+    tracing_subscriber::fmt::init();
+
+    let cfg = match Config::from_args() {
+        Ok(x) => Arc::new(x),
+        Err(e) => {
+            eprintln!("{:#}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let mut client_cfg = quinn::ClientConfig::default();
+    Arc::get_mut(&mut client_cfg.crypto)
+        .unwrap()
+        .dangerous()
+        .set_certificate_verifier(Arc::new(AcceptAnyCert));
+
+    let (events_send, events) = mpsc::unbounded_channel();
+    let spawner = {
+        let cfg = cfg.clone();
+        async move {
+            for i in 0..cfg.players {
+                tokio::spawn(player(
+                    cfg.clone(),
+                    client_cfg.clone(),
+                    i,
+                    events_send.clone(),
+                ));
+                // Don't overwhelm the server with simultaneous handshakes
+                tokio::time::delay_for(CONNECT_INTERVAL).await;
+            }
+        }
+    };
+    tokio::spawn(spawner);
+
+    let stop = async {
+        match cfg.duration {
+            Some(duration) => tokio::time::delay_for(duration).await,
+            None => future::pending().await,
+        }
+    }
+    .fuse();
+    let interrupt = tokio::signal::ctrl_c().fuse();
+    pin_mut!(stop, interrupt);
+    let mut events = events.fuse();
+    let mut reports = tokio::time::interval(REPORT_INTERVAL).fuse();
+    // The first tick is immediate
+    reports.next().await;
+
+    let mut recent = Stats::default();
+    let mut total = Stats::default();
+    loop {
+        select! {
+            e = events.select_next_some() => { recent.record(e); }
+            _ = reports.next() => {
+                recent.report("last interval");
+                total.merge(&recent);
+                recent = Stats::default();
+            }
+            () = stop => { break; }
+            _ = interrupt => { break; }
+        }
+    }
+    total.merge(&recent);
+    total.report("total");
+}
+
+/// Run a single simulated player until it's disconnected
+async fn player(
+    cfg: Arc<Config>,
+    client_cfg: quinn::ClientConfig,
+    index: u32,
+    events: mpsc::UnboundedSender<Event>,
+) {
+    let name = format!("bot-{}", index);
+    let hello = proto::ClientHello {
+        protocol_version: proto::PROTOCOL_VERSION,
+        capabilities: proto::Capabilities::SUPPORTED,
+        credential: credential(&name),
+        name,
+    };
+    let session = match session::connect(&cfg.server, &cfg.server_name, client_cfg, &hello).await {
+        Ok(x) => x,
+        Err(e) => {
+            let _ = events.send(Event::ConnectFailed(Disconnect::new(e)));
+            return;
+        }
+    };
+    let _ = events.send(Event::Connected);
+    if let Err(e) = drive(&cfg, session, &events).await {
+        let _ = events.send(Event::Disconnected(Disconnect::new(e)));
+    }
+}
+
+/// Credential for the bot called `name`
+///
+/// Derived from the name so that bots reclaim their characters on subsequent runs. Anyone can
+/// derive it, which is tolerable only because bots have nothing to protect.
+fn credential(name: &str) -> [u8; 32] {
+    let mut result = [0; 32];
+    result.copy_from_slice(ring::digest::digest(&ring::digest::SHA256, name.as_bytes()).as_ref());
+    result
+}
+
+/// Exchange messages with the server until the connection is lost
+async fn drive(
+    cfg: &Config,
+    session: Session,
+    events: &mpsc::UnboundedSender<Event>,
+) -> Result<()> {
+    let Session {
+        endpoint: _endpoint,
+        connection,
+        uni_streams,
+        ordered,
+        hello,
+    } = session;
+    // Server-side node of the player's character, as last reported by the server
+    let node = Mutex::new(NodeId::ROOT);
+    let rate = cfg.rate.unwrap_or_else(|| f64::from(hello.rate));
+    future::try_join3(
+        handle_ordered(events, ordered),
+        handle_unordered(events, uni_streams, hello.character, &node),
+        handle_outgoing(rate, connection, &node),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Receive ordered messages from the server
+///
+/// The server only finishes the ordered stream as it closes the connection, so the reason for the
+/// close is left for the other tasks to discover.
+async fn handle_ordered(
+    events: &mpsc::UnboundedSender<Event>,
+    mut ordered: quinn::RecvStream,
+) -> Result<()> {
+    while let Some(spawns) = codec::recv::<proto::Spawns>(&mut ordered).await? {
+        let _ = events.send(Event::Received {
+            step: spawns.step,
+            bytes: codec::framed_size(&spawns),
+            kind: MessageKind::Spawns,
+        });
+    }
+    Ok(())
+}
+
+/// Receive unordered messages from the server, tracking the node `character` occupies
+async fn handle_unordered(
+    events: &mpsc::UnboundedSender<Event>,
+    uni_streams: quinn::IncomingUniStreams,
+    character: EntityId,
+    node: &Mutex<NodeId>,
+) -> Result<()> {
+    let mut msgs = uni_streams
+        .map(|stream| async {
+            let bytes = codec::recv_whole_bytes(2usize.pow(16), stream?).await?;
+            let delta = bincode::deserialize::<proto::StateDelta>(&bytes)?;
+            Ok::<_, Error>((bytes.len() as u64, delta))
+        })
+        .buffer_unordered(128);
+    while let Some((bytes, delta)) = msgs.try_next().await? {
+        let _ = events.send(Event::Received {
+            step: delta.step,
+            bytes,
+            kind: MessageKind::StateDelta,
+        });
+        if let Some(&(_, ref position)) = delta.positions.iter().find(|x| x.0 == character) {
+            *node.lock().unwrap() = position.node;
+        }
+    }
+    Ok(())
+}
+
+/// Send `rate` commands per second to the server
+async fn handle_outgoing(
+    rate: f64,
+    connection: quinn::Connection,
+    node: &Mutex<NodeId>,
+) -> Result<()> {
+    let mut ticks = tokio::time::interval(Duration::from_secs_f64(1.0 / rate));
+    let start = Instant::now();
+    let mut step = 0;
+    loop {
+        ticks.tick().await;
+        step += 1;
+        // Fly in a circle, so that the server has some motion to report
+        let t = start.elapsed().as_secs_f32();
+        let msg = ClientMessage::Command(Command {
+            step,
+            node: *node.lock().unwrap(),
+            orientation: na::UnitQuaternion::identity(),
+            velocity: na::Vector3::new(t.cos(), 0.0, t.sin()),
+            mode: MovementMode::Fly,
+            jump: false,
+            crouch: false,
+        });
+        codec::send_whole(connection.open_uni().await?, &msg).await?;
+    }
+}
+
+/// Accepts every certificate; acceptable only because bots have nothing to protect
+struct AcceptAnyCert;
+
+impl rustls::ServerCertVerifier for AcceptAnyCert {
+    fn verify_server_cert(
+        &self,
+        _roots: &rustls::RootCertStore,
+        _presented_certs: &[rustls::Certificate],
+        _dns_name: webpki::DNSNameRef,
+        _ocsp_response: &[u8],
+    ) -> Result<rustls::ServerCertVerified, rustls::TLSError> {
+        Ok(rustls::ServerCertVerified::assertion())
+    }
+}
+
+/// Delay between successive players connecting
+const CONNECT_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How often to log statistics
+const REPORT_INTERVAL: Duration = Duration::from_secs(5);
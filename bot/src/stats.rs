@@ -0,0 +1,141 @@
+use fxhash::FxHashMap;
+use tracing::{info, warn};
+
+use common::{proto::DisconnectReason, session::Disconnect, Step};
+
+/// Something that happened to a simulated player
+pub enum Event {
+    Connected,
+    ConnectFailed(Disconnect),
+    Disconnected(Disconnect),
+    /// A message of `bytes` bytes describing `step` arrived
+    Received {
+        step: Step,
+        bytes: u64,
+        kind: MessageKind,
+    },
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum MessageKind {
+    Spawns,
+    StateDelta,
+}
+
+/// Aggregate of events over some period
+#[derive(Default)]
+pub struct Stats {
+    connected: u32,
+    connect_failures: u32,
+    disconnects: u32,
+    /// Disconnects due to not keeping up with the server
+    too_slow: u32,
+    /// Total bytes received by all players for each step
+    ticks: FxHashMap<Step, Tick>,
+}
+
+#[derive(Default, Copy, Clone)]
+struct Tick {
+    spawns: u64,
+    state_deltas: u64,
+    /// Largest state delta received by a single player
+    max_state_delta: u64,
+}
+
+impl Stats {
+    pub fn record(&mut self, event: Event) {
+        match event {
+            Event::Connected => self.connected += 1,
+            Event::ConnectFailed(e) => {
+                warn!("failed to connect: {}", e);
+                self.connect_failures += 1;
+            }
+            Event::Disconnected(e) => {
+                warn!("disconnected: {}", e);
+                self.disconnects += 1;
+                if e.reason() == Some(DisconnectReason::TooSlow) {
+                    self.too_slow += 1;
+                }
+            }
+            Event::Received { step, bytes, kind } => {
+                let tick = self.ticks.entry(step).or_default();
+                match kind {
+                    MessageKind::Spawns => tick.spawns += bytes,
+                    MessageKind::StateDelta => {
+                        tick.state_deltas += bytes;
+                        tick.max_state_delta = tick.max_state_delta.max(bytes);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Log a summary, headed by `label`
+    pub fn report(&self, label: &str) {
+        let ticks = self.ticks.len().max(1) as u64;
+        let (spawns, state_deltas, max_total, max_state_delta) = self.ticks.values().fold(
+            (0, 0, 0, 0),
+            |(spawns, state_deltas, max_total, max_state_delta), tick| {
+                (
+                    spawns + tick.spawns,
+                    state_deltas + tick.state_deltas,
+                    max_total.max(tick.spawns + tick.state_deltas),
+                    max_state_delta.max(tick.max_state_delta),
+                )
+            },
+        );
+        info!(
+            "{}: {} connected, {} failed to connect, {} disconnected ({} too slow); \
+             {} ticks averaging {} spawn bytes and {} state delta bytes, at most {} bytes total \
+             and {} bytes in one player's state delta",
+            label,
+            self.connected,
+            self.connect_failures,
+            self.disconnects,
+            self.too_slow,
+            self.ticks.len(),
+            spawns / ticks,
+            state_deltas / ticks,
+            max_total,
+            max_state_delta,
+        );
+    }
+
+    /// Incorporate the events recorded by `other`
+    pub fn merge(&mut self, other: &Stats) {
+        self.connected += other.connected;
+        self.connect_failures += other.connect_failures;
+        self.disconnects += other.disconnects;
+        self.too_slow += other.too_slow;
+        for (&step, tick) in &other.ticks {
+            let total = self.ticks.entry(step).or_default();
+            total.spawns += tick.spawns;
+            total.state_deltas += tick.state_deltas;
+            total.max_state_delta = total.max_state_delta.max(tick.max_state_delta);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merge_ticks() {
+        let received = |step, bytes, kind| Event::Received { step, bytes, kind };
+        let mut a = Stats::default();
+        a.record(received(1, 10, MessageKind::Spawns));
+        a.record(received(1, 20, MessageKind::StateDelta));
+        let mut b = Stats::default();
+        b.record(Event::Connected);
+        b.record(received(1, 30, MessageKind::StateDelta));
+        b.record(received(2, 5, MessageKind::StateDelta));
+        a.merge(&b);
+        assert_eq!(a.connected, 1);
+        assert_eq!(a.ticks.len(), 2);
+        let tick = a.ticks[&1];
+        assert_eq!(tick.spawns, 10);
+        assert_eq!(tick.state_deltas, 50);
+        assert_eq!(tick.max_state_delta, 30);
+    }
+}
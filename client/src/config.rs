@@ -1,28 +1,43 @@
-use std::{fs, io, path::PathBuf, sync::Arc};
+use std::{
+    fs, io,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
+use anyhow::{anyhow, bail, Result};
+use rand::RngCore;
 use serde::Deserialize;
-use tracing::{error, info};
+use tracing::{error, info, warn};
 
 pub struct Config {
     pub name: Arc<str>,
+    /// Secret identifying us to servers, so that we regain control of our character on reconnect
+    pub credential: [u8; 32],
     pub data_dir: PathBuf,
     pub view_distance: f64,
     pub chunks_loaded_per_frame: u32,
-    pub input_send_rate: u16,
+    /// Address of the server to connect to
+    ///
+    /// In singleplayer mode, this is set once the in-process server is listening.
+    pub server: String,
+    /// Name the server's certificate is expected to be issued for
+    pub server_name: String,
+    /// Whether to host a private server in-process rather than connecting to `server`
+    pub singleplayer: bool,
+    /// Server recording to play back rather than connecting to a server
+    pub replay: Option<PathBuf>,
+    /// Name of the player whose session to play back from `replay`; the first recorded if unset
+    pub replay_as: Option<String>,
 }
 
 impl Config {
-    pub fn load(dirs: &directories::ProjectDirs) -> Self {
+    /// Load the config file, with overrides from the command line
+    pub fn load(dirs: &directories::ProjectDirs) -> Result<Self> {
         // Future work: search $XDG_CONFIG_DIRS
         let path = dirs.config_dir().join("client.toml");
         // Read and parse config file
-        let RawConfig {
-            name,
-            data_dir,
-            view_distance,
-            chunks_loaded_per_frame,
-            input_send_rate,
-        } = match fs::read(&path) {
+        let raw = match fs::read(&path) {
             Ok(data) => match toml::from_slice(&data) {
                 Ok(x) => x,
                 Err(e) => {
@@ -39,15 +54,108 @@ impl Config {
                 RawConfig::default()
             }
         };
+        let RawConfig {
+            name,
+            data_dir,
+            view_distance,
+            chunks_loaded_per_frame,
+            server,
+            server_name,
+            singleplayer,
+            replay,
+            replay_as,
+        } = raw.override_from(std::env::args().skip(1))?;
         // Massage into final form
-        Config {
+        let data_dir = data_dir.unwrap_or_else(|| dirs.data_dir().into());
+        let singleplayer = singleplayer.unwrap_or(false);
+        if singleplayer && replay.is_some() {
+            bail!("can't play back a recording in singleplayer mode");
+        }
+        let (server, server_name) = if singleplayer {
+            (String::new(), SINGLEPLAYER_NAME.into())
+        } else {
+            let server = server.unwrap_or_else(|| "localhost:1234".into());
+            let server_name = match server_name {
+                Some(x) => x,
+                None => server_name_of(&server)?.into(),
+            };
+            (server, server_name)
+        };
+        Ok(Config {
             name: name.unwrap_or_else(|| whoami::user().into()),
-            data_dir: data_dir.unwrap_or_else(|| dirs.data_dir().into()),
+            credential: load_credential(&data_dir),
+            data_dir,
             view_distance: view_distance.unwrap_or(3.0),
             chunks_loaded_per_frame: chunks_loaded_per_frame.unwrap_or(16),
-            input_send_rate: input_send_rate.unwrap_or(30),
+            server,
+            server_name,
+            singleplayer,
+            replay,
+            replay_as,
+        })
+    }
+}
+
+/// Name of the in-process server in singleplayer mode
+///
+/// Distinct from any real host so that its certificate isn't confused with that of a server
+/// running on this machine.
+pub const SINGLEPLAYER_NAME: &str = "singleplayer";
+
+/// Extract the host from a `host:port` address
+fn host(address: &str) -> Result<&str> {
+    let host = if address.starts_with('[') {
+        // Bracketed IPv6 address
+        address[1..].split(']').next().unwrap()
+    } else {
+        match address.rfind(':') {
+            Some(i) => &address[..i],
+            None => address,
         }
+    };
+    if host.is_empty() {
+        bail!("no host in server address {:?}", address);
     }
+    Ok(host)
+}
+
+/// Guess the name the certificate of the server at `address` is issued for
+///
+/// Certificates name DNS hosts, so an IP address can't serve as a guess.
+fn server_name_of(address: &str) -> Result<&str> {
+    let host = host(address)?;
+    if host.parse::<IpAddr>().is_ok() {
+        bail!(
+            "server address {:?} doesn't include a host name; specify the name the server's \
+             certificate is issued for with --server-name",
+            address
+        );
+    }
+    Ok(host)
+}
+
+/// Read our credential from `data_dir`, generating and storing a new one if necessary
+fn load_credential(data_dir: &Path) -> [u8; 32] {
+    let path = data_dir.join("credential");
+    let mut credential = [0; 32];
+    match fs::read(&path) {
+        Ok(ref data) if data.len() == credential.len() => {
+            credential.copy_from_slice(data);
+            return credential;
+        }
+        Ok(_) => error!("{} is malformed, replacing it", path.display()),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => error!("failed to read credential: {}: {}", path.display(), e),
+    }
+    rand::thread_rng().fill_bytes(&mut credential);
+    if let Err(e) = fs::create_dir_all(data_dir).and_then(|()| fs::write(&path, &credential)) {
+        warn!(
+            "failed to save credential, characters won't be restored on reconnect: {}: {}",
+            path.display(),
+            e
+        );
+    }
+    credential
 }
 
 /// Data as parsed directly out of the config file
@@ -57,5 +165,71 @@ struct RawConfig {
     data_dir: Option<PathBuf>,
     view_distance: Option<f64>,
     chunks_loaded_per_frame: Option<u32>,
-    input_send_rate: Option<u16>,
+    server: Option<String>,
+    server_name: Option<String>,
+    singleplayer: Option<bool>,
+    replay: Option<PathBuf>,
+    replay_as: Option<String>,
+}
+
+impl RawConfig {
+    /// Apply command-line overrides
+    fn override_from(mut self, mut args: impl Iterator<Item = String>) -> Result<Self> {
+        while let Some(arg) = args.next() {
+            match &arg[..] {
+                "--server" => {
+                    self.server = Some(
+                        args.next()
+                            .ok_or_else(|| anyhow!("--server requires an address"))?,
+                    );
+                }
+                "--server-name" => {
+                    self.server_name = Some(
+                        args.next()
+                            .ok_or_else(|| anyhow!("--server-name requires a name"))?,
+                    );
+                }
+                "--singleplayer" => {
+                    self.singleplayer = Some(true);
+                }
+                "--replay" => {
+                    self.replay = Some(
+                        args.next()
+                            .ok_or_else(|| anyhow!("--replay requires a path"))?
+                            .into(),
+                    );
+                }
+                "--replay-as" => {
+                    self.replay_as = Some(
+                        args.next()
+                            .ok_or_else(|| anyhow!("--replay-as requires a name"))?,
+                    );
+                }
+                _ => bail!("unrecognized argument {:?}", arg),
+            }
+        }
+        Ok(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn server_host() {
+        assert_eq!(host("example.com:1234").unwrap(), "example.com");
+        assert_eq!(host("example.com").unwrap(), "example.com");
+        assert_eq!(host("127.0.0.1:1234").unwrap(), "127.0.0.1");
+        assert_eq!(host("[::1]:1234").unwrap(), "::1");
+        assert!(host(":1234").is_err());
+    }
+
+    #[test]
+    fn guess_server_name() {
+        assert_eq!(server_name_of("example.com:1234").unwrap(), "example.com");
+        assert_eq!(server_name_of("localhost").unwrap(), "localhost");
+        assert!(server_name_of("127.0.0.1:1234").is_err());
+        assert!(server_name_of("[::1]:1234").is_err());
+    }
 }
@@ -14,10 +14,12 @@ use tracing::warn;
 use super::lru_table::LruTable;
 use crate::{
     graphics::{Base, Loader},
-    sim::VoxelData,
     Config, Sim,
 };
-use common::{graph::NodeId, world::SUBDIVISION_FACTOR};
+use common::{
+    graph::NodeId,
+    world::{VoxelData, SUBDIVISION_FACTOR},
+};
 
 use surface::Surface;
 use surface_extraction::{DrawBuffer, ScratchBuffer, SurfaceExtraction};
@@ -86,62 +88,82 @@ impl Voxels {
             // Fetch existing chunk, or extract surface of new chunk
             let slot = match *sim.graph.get_cube_mut(node, cube) {
                 None => continue,
-                Some(ref mut value) => match (value.surface, &value.voxels) {
-                    (Some(x), _) => {
-                        self.states.get_mut(x).refcount += 1;
-                        x
+                Some(ref mut value) => {
+                    if value.dirty {
+                        match value.surface {
+                            // Discard the outdated surface once no frame in flight is drawing it
+                            Some(x) if self.states.peek(x).refcount == 0 => {
+                                self.states.remove(x);
+                                value.surface = None;
+                                value.dirty = false;
+                            }
+                            // Stop drawing it in the meantime so that it's released promptly
+                            Some(_) => continue,
+                            None => value.dirty = false,
+                        }
                     }
-                    (None, &VoxelData::Dense(ref data)) => {
-                        if frame.extracted.len() == self.config.chunks_loaded_per_frame as usize {
-                            continue;
+                    match (value.surface, &value.voxels) {
+                        (Some(x), _) => {
+                            self.states.get_mut(x).refcount += 1;
+                            x
                         }
-                        let removed = if self.states.is_full() {
-                            let slot = self.states.lru().unwrap();
-                            if self.states.peek(slot).refcount != 0 {
-                                warn!("MAX_CHUNKS is too small");
-                                break;
+                        (None, &VoxelData::Dense(ref data)) => {
+                            if frame.extracted.len() == self.config.chunks_loaded_per_frame as usize
+                            {
+                                continue;
+                            }
+                            let removed = if self.states.is_full() {
+                                let slot = self.states.lru().unwrap();
+                                if self.states.peek(slot).refcount != 0 {
+                                    warn!("MAX_CHUNKS is too small");
+                                    break;
+                                }
+                                Some(self.states.remove(slot))
+                            } else {
+                                None
+                            };
+                            let scratch_slot = self.extraction_scratch.alloc().unwrap();
+                            frame.extracted.push(scratch_slot);
+                            let slot = self
+                                .states
+                                .insert(SurfaceState {
+                                    epoch: sim.graph_epoch(),
+                                    node,
+                                    cube,
+                                    refcount: 1,
+                                })
+                                .unwrap();
+                            value.surface = Some(slot);
+                            let storage = self.extraction_scratch.storage(scratch_slot);
+                            storage.copy_from_slice(&data[..]);
+                            if let Some(lru) = removed {
+                                // Chunks of a discarded graph have no surfaces to forget
+                                if lru.epoch == sim.graph_epoch() {
+                                    sim.graph
+                                        .get_cube_mut(lru.node, lru.cube)
+                                        .as_mut()
+                                        .unwrap()
+                                        .surface = None;
+                                }
                             }
-                            Some(self.states.remove(slot))
-                        } else {
-                            None
-                        };
-                        let scratch_slot = self.extraction_scratch.alloc().unwrap();
-                        frame.extracted.push(scratch_slot);
-                        let slot = self
-                            .states
-                            .insert(SurfaceState {
-                                node,
-                                cube,
-                                refcount: 1,
-                            })
-                            .unwrap();
-                        value.surface = Some(slot);
-                        let storage = self.extraction_scratch.storage(scratch_slot);
-                        storage.copy_from_slice(&data[..]);
-                        if let Some(lru) = removed {
-                            sim.graph
-                                .get_cube_mut(lru.node, lru.cube)
-                                .as_mut()
-                                .unwrap()
-                                .surface = None;
+                            self.extraction_scratch.extract(
+                                &self.surface_extraction,
+                                scratch_slot,
+                                cmd,
+                                (
+                                    self.surfaces.indirect_buffer(),
+                                    self.surfaces.indirect_offset(slot.0),
+                                ),
+                                (
+                                    self.surfaces.face_buffer(),
+                                    self.surfaces.face_offset(slot.0),
+                                ),
+                            );
+                            slot
                         }
-                        self.extraction_scratch.extract(
-                            &self.surface_extraction,
-                            scratch_slot,
-                            cmd,
-                            (
-                                self.surfaces.indirect_buffer(),
-                                self.surfaces.indirect_offset(slot.0),
-                            ),
-                            (
-                                self.surfaces.face_buffer(),
-                                self.surfaces.face_offset(slot.0),
-                            ),
-                        );
-                        slot
+                        (None, &VoxelData::Empty) => continue,
                     }
-                    (None, &VoxelData::Empty) => continue,
-                },
+                }
             };
             frame.drawn.push(DrawnChunk {
                 slot,
@@ -221,6 +243,8 @@ impl Frame {
 const MAX_CHUNKS: u32 = 4096;
 
 struct SurfaceState {
+    /// `Sim::graph_epoch` as of when `node` and `cube` were recorded
+    epoch: u64,
     node: NodeId,
     cube: common::dodeca::Vertex,
     refcount: u32,
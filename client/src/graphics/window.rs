@@ -7,13 +7,18 @@ use ash::{extensions::khr, version::DeviceV1_0, vk};
 use lahar::DedicatedImage;
 use tracing::info;
 use winit::{
-    event::{DeviceEvent, ElementState, Event, KeyboardInput, VirtualKeyCode, WindowEvent},
+    event::{
+        DeviceEvent, ElementState, Event, KeyboardInput, MouseButton, VirtualKeyCode, WindowEvent,
+    },
     event_loop::{ControlFlow, EventLoop},
     window::{Window as WinitWindow, WindowBuilder},
 };
 
 use super::{Base, Core, Draw};
-use crate::{Config, Sim};
+use crate::{Config, LocalServer, Sim};
+use common::proto::MAX_CHAT_LEN;
+
+const TITLE: &str = "hypermine";
 
 /// OS window
 pub struct EarlyWindow {
@@ -25,7 +30,7 @@ impl EarlyWindow {
     pub fn new() -> Self {
         let event_loop = EventLoop::new();
         let window = WindowBuilder::new()
-            .with_title("hypermine")
+            .with_title(TITLE)
             .build(&event_loop)
             .unwrap();
         Self { event_loop, window }
@@ -48,11 +53,19 @@ pub struct Window {
     swapchain: Option<SwapchainMgr>,
     draw: Option<Draw>,
     sim: Sim,
+    /// Server to shut down when the window is closed, in singleplayer mode
+    local_server: Option<LocalServer>,
 }
 
 impl Window {
     /// Finish constructing a window
-    pub fn new(early: EarlyWindow, core: Arc<Core>, config: Arc<Config>, sim: Sim) -> Self {
+    pub fn new(
+        early: EarlyWindow,
+        core: Arc<Core>,
+        config: Arc<Config>,
+        sim: Sim,
+        local_server: Option<LocalServer>,
+    ) -> Self {
         let surface = unsafe {
             ash_window::create_surface(&core.entry, &core.instance, &early.window, None).unwrap()
         };
@@ -68,6 +81,7 @@ impl Window {
             swapchain: None,
             draw: None,
             sim,
+            local_server,
         }
     }
 
@@ -96,8 +110,14 @@ impl Window {
         let mut down = false;
         let mut clockwise = false;
         let mut anticlockwise = false;
+        let mut toggling_movement_mode = false;
         let mut last_frame = Instant::now();
         let mut focused = true;
+        // Whether the cursor is captured for looking around
+        let mut grabbed = false;
+        // Chat message being composed, if any
+        let mut draft: Option<String> = None;
+        let mut title = String::from(TITLE);
         self.event_loop
             .take()
             .unwrap()
@@ -116,16 +136,23 @@ impl Window {
                     let this_frame = Instant::now();
                     self.sim.step(this_frame - last_frame);
                     last_frame = this_frame;
+
+                    // Show the latest chat message, or the one being composed
+                    let new_title = match draft {
+                        Some(ref x) => format!("{} - say: {}", TITLE, x),
+                        None => match self.sim.chat_log().back() {
+                            Some(msg) => format!("{} - {}: {}", TITLE, msg.name, msg.text),
+                            None => TITLE.into(),
+                        },
+                    };
+                    if new_title != title {
+                        self.window.set_title(&new_title);
+                        title = new_title;
+                    }
+
                     self.draw();
                 }
                 Event::DeviceEvent { event, .. } => match event {
-                    DeviceEvent::Button {
-                        button: 1,
-                        state: ElementState::Pressed,
-                    } => {
-                        let _ = self.window.set_cursor_grab(true);
-                        self.window.set_cursor_visible(false);
-                    }
                     DeviceEvent::MouseMotion { delta } if focused => {
                         const SENSITIVITY: f32 = 2e-3;
                         let rot = na::UnitQuaternion::from_axis_angle(
@@ -140,10 +167,65 @@ impl Window {
                     _ => {}
                 },
                 Event::WindowEvent { event, .. } => match event {
+                    WindowEvent::MouseInput {
+                        state: ElementState::Pressed,
+                        button,
+                        ..
+                    } => {
+                        if !grabbed {
+                            // The first click only captures the cursor
+                            if button == MouseButton::Left {
+                                let _ = self.window.set_cursor_grab(true);
+                                self.window.set_cursor_visible(false);
+                                grabbed = true;
+                            }
+                        } else if draft.is_none() {
+                            match button {
+                                MouseButton::Left => self.sim.break_block(),
+                                MouseButton::Right => self.sim.place_block(),
+                                _ => {}
+                            }
+                        }
+                    }
                     WindowEvent::CloseRequested => {
                         info!("exiting due to closed window");
+                        if let Some(server) = self.local_server.take() {
+                            // Save recent changes to the world before the process exits
+                            server.shutdown();
+                        }
                         *control_flow = ControlFlow::Exit;
                     }
+                    WindowEvent::KeyboardInput {
+                        input:
+                            KeyboardInput {
+                                state: ElementState::Pressed,
+                                virtual_keycode: Some(key),
+                                ..
+                            },
+                        ..
+                    } if draft.is_some() => match key {
+                        VirtualKeyCode::Return => {
+                            let text = draft.take().unwrap();
+                            if !text.trim().is_empty() {
+                                self.sim.chat(text);
+                            }
+                        }
+                        VirtualKeyCode::Back => {
+                            draft.as_mut().unwrap().pop();
+                        }
+                        VirtualKeyCode::Escape => {
+                            draft = None;
+                        }
+                        _ => {}
+                    },
+                    WindowEvent::ReceivedCharacter(c) => {
+                        if let Some(ref mut draft) = draft {
+                            if !c.is_control() && draft.len() + c.len_utf8() <= MAX_CHAT_LEN {
+                                draft.push(c);
+                            }
+                        }
+                    }
+                    WindowEvent::KeyboardInput { .. } if draft.is_some() => {}
                     WindowEvent::KeyboardInput {
                         input:
                             KeyboardInput {
@@ -177,9 +259,38 @@ impl Window {
                         VirtualKeyCode::F => {
                             down = state == ElementState::Pressed;
                         }
+                        VirtualKeyCode::Space => {
+                            self.sim.jump(state == ElementState::Pressed);
+                        }
+                        VirtualKeyCode::LControl => {
+                            self.sim.crouch(state == ElementState::Pressed);
+                        }
+                        VirtualKeyCode::V => {
+                            // Ignore key repeat
+                            let pressed = state == ElementState::Pressed;
+                            if pressed && !toggling_movement_mode {
+                                self.sim.toggle_movement_mode();
+                            }
+                            toggling_movement_mode = pressed;
+                        }
                         VirtualKeyCode::Escape => {
                             let _ = self.window.set_cursor_grab(false);
                             self.window.set_cursor_visible(true);
+                            grabbed = false;
+                        }
+                        VirtualKeyCode::Return if state == ElementState::Pressed => {
+                            // Start composing a chat message, which captures the keyboard
+                            forward = false;
+                            back = false;
+                            left = false;
+                            right = false;
+                            up = false;
+                            down = false;
+                            clockwise = false;
+                            anticlockwise = false;
+                            self.sim.jump(false);
+                            self.sim.crouch(false);
+                            draft = Some(String::new());
                         }
                         _ => {}
                     },
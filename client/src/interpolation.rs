@@ -0,0 +1,139 @@
+use std::collections::VecDeque;
+
+use common::{dodeca::Side, graph::Graph, math, proto::Position, Step};
+
+/// Recent authoritative positions of an entity, for smooth display between updates
+pub struct PositionHistory {
+    /// Oldest first, with strictly increasing steps
+    samples: VecDeque<(Step, Position)>,
+}
+
+impl PositionHistory {
+    pub fn new(step: Step, position: Position) -> Self {
+        let mut samples = VecDeque::with_capacity(MAX_SAMPLES);
+        samples.push_back((step, position));
+        Self { samples }
+    }
+
+    /// Record the entity's position as of `step`
+    ///
+    /// Positions are only sent when they change, so the entity is assumed to have remained where it
+    /// was during any steps since the previous sample.
+    pub fn push(&mut self, step: Step, position: Position) {
+        let (latest_step, latest) = *self.samples.back().unwrap();
+        if step <= latest_step {
+            // Updates may be reordered; only the most recent matter
+            return;
+        }
+        if step > latest_step + 1 {
+            self.push_sample(step - 1, latest);
+        }
+        self.push_sample(step, position);
+    }
+
+    fn push_sample(&mut self, step: Step, position: Position) {
+        if self.samples.len() == MAX_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((step, position));
+    }
+
+    /// Estimate the entity's position at `time`, measured in steps
+    ///
+    /// Times outside the recorded history are clamped to it. Entities that jumped between
+    /// non-adjacent nodes between samples aren't interpolated.
+    pub fn sample<N, C>(&self, graph: &Graph<N, C>, time: f64) -> Position {
+        let next = self
+            .samples
+            .iter()
+            .position(|&(step, _)| step as f64 > time);
+        let (prev, next) = match next {
+            None => return self.samples.back().unwrap().1,
+            Some(0) => return self.samples[0].1,
+            Some(i) => (self.samples[i - 1], self.samples[i]),
+        };
+        let t = ((time - prev.0 as f64) / (next.0 - prev.0) as f64) as f32;
+        let next_local = if prev.1.node == next.1.node {
+            next.1.local
+        } else {
+            match Side::iter().find(|&side| graph.neighbor(prev.1.node, side) == Some(next.1.node))
+            {
+                Some(side) => side.reflection().map(|x| x as f32) * next.1.local,
+                None => return if t < 0.5 { prev.1 } else { next.1 },
+            }
+        };
+        let local = math::interpolate_isometry(&prev.1.local, &next_local, t);
+        let (node, transition) = graph.normalize_transform(prev.1.node, &local);
+        Position {
+            node,
+            local: transition * local,
+        }
+    }
+}
+
+/// Number of samples retained, bounding the delay that can be interpolated over
+const MAX_SAMPLES: usize = 8;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::graph::NodeId;
+
+    fn at(node: NodeId, distance: f32) -> Position {
+        Position {
+            node,
+            local: math::translate_along(&na::Vector3::x_axis(), distance),
+        }
+    }
+
+    #[test]
+    fn interpolate_within_node() {
+        let graph = Graph::<(), ()>::new();
+        let mut history = PositionHistory::new(10, at(NodeId::ROOT, 0.0));
+        history.push(12, at(NodeId::ROOT, 0.4));
+        // Stale samples are discarded
+        history.push(11, at(NodeId::ROOT, 5.0));
+
+        let x = |time| {
+            let position = history.sample(&graph, time);
+            assert_eq!(position.node, NodeId::ROOT);
+            (position.local * math::origin::<f32>()).x
+        };
+        assert_eq!(x(9.0), 0.0);
+        // No update for step 11 means the entity didn't move then
+        assert_eq!(x(11.0), 0.0);
+        assert!((x(11.5) - 0.2f32.sinh()).abs() < 1e-5);
+        assert!((x(13.0) - 0.4f32.sinh()).abs() < 1e-5);
+    }
+
+    #[test]
+    fn interpolate_across_nodes() {
+        let mut graph = Graph::<(), ()>::new();
+        graph.ensure_nearby(NodeId::ROOT, 2);
+        let neighbor = graph.neighbor(NodeId::ROOT, Side::A).unwrap();
+        let start = Position {
+            node: NodeId::ROOT,
+            local: na::Matrix4::identity(),
+        };
+        let end = Position {
+            node: neighbor,
+            local: na::Matrix4::identity(),
+        };
+        let mut history = PositionHistory::new(0, start);
+        history.push(1, end);
+
+        // Halfway between the centers of adjacent nodes lies on the side they share
+        let mid = history.sample(&graph, 0.5);
+        let p = mid.local.map(|x| x as f64) * math::origin();
+        let p = if mid.node == NodeId::ROOT {
+            p
+        } else {
+            assert_eq!(mid.node, neighbor);
+            Side::A.reflection() * p
+        };
+        let center = Side::A.reflection() * math::origin();
+        let half = math::distance(&math::origin(), &center) / 2.0;
+        assert!((math::distance(&math::origin(), &p) - half).abs() < 1e-3);
+        assert!((math::distance(&center, &p) - half).abs() < 1e-3);
+    }
+}
@@ -0,0 +1,231 @@
+//! Verification of servers' certificates, trusting self-signed certificates on first use
+//!
+//! Certificates that chain to a trusted root are accepted as usual. Otherwise, the fingerprint of
+//! the certificate a server presents on first contact is recorded, and any different certificate
+//! subsequently presented under the same name is rejected, much like SSH's `known_hosts`.
+
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::{Context, Result};
+use fxhash::FxHashMap;
+use tracing::{error, warn};
+
+/// SHA-256 digest of a DER-encoded certificate
+pub type Fingerprint = [u8; 32];
+
+pub fn fingerprint(certificate: &[u8]) -> Fingerprint {
+    let mut result = [0; 32];
+    result.copy_from_slice(ring::digest::digest(&ring::digest::SHA256, certificate).as_ref());
+    result
+}
+
+/// Fingerprints of the certificates of servers we've previously trusted, by name
+#[derive(Default)]
+pub struct KnownServers {
+    fingerprints: FxHashMap<String, Fingerprint>,
+}
+
+impl KnownServers {
+    /// Read the records at `path`, if any
+    pub fn load(path: &Path) -> Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(text) => Ok(Self::parse(&text)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e).with_context(|| format!("reading {}", path.display())),
+        }
+    }
+
+    /// Parse records consisting of a server name and a hex-encoded fingerprint, one per line
+    fn parse(text: &str) -> Self {
+        let mut fingerprints = FxHashMap::default();
+        for line in text.lines() {
+            let mut fields = line.split_whitespace();
+            let (name, fingerprint) = match (fields.next(), fields.next().and_then(decode)) {
+                (Some(name), Some(fingerprint)) => (name, fingerprint),
+                (None, _) => continue,
+                (Some(_), None) => {
+                    warn!("ignoring malformed known server record {:?}", line);
+                    continue;
+                }
+            };
+            fingerprints.insert(name.into(), fingerprint);
+        }
+        Self { fingerprints }
+    }
+
+    /// Judge a certificate with fingerprint `fingerprint` presented by `name`, trusting it from now
+    /// on if `name` was previously unknown
+    fn check(&mut self, name: &str, fingerprint: &Fingerprint) -> Trust {
+        match self.fingerprints.get(name) {
+            Some(known) if known == fingerprint => Trust::Known,
+            Some(_) => Trust::Changed,
+            None => {
+                self.fingerprints.insert(name.into(), *fingerprint);
+                Trust::New
+            }
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Trust {
+    /// The server presented the certificate it did previously
+    Known,
+    /// The server hasn't been seen before
+    New,
+    /// The server presented a different certificate than it did previously
+    Changed,
+}
+
+fn encode(fingerprint: &Fingerprint) -> String {
+    fingerprint.iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+fn decode(hex: &str) -> Option<Fingerprint> {
+    let mut result = [0; 32];
+    if hex.len() != 2 * result.len() || !hex.is_ascii() {
+        return None;
+    }
+    for (i, byte) in result.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).ok()?;
+    }
+    Some(result)
+}
+
+/// Accepts certificates that chain to a trusted root, or that are pinned in a `KnownServers`
+pub struct CertVerifier {
+    webpki: rustls::WebPKIVerifier,
+    /// Where newly trusted servers are recorded
+    path: PathBuf,
+    known: Mutex<KnownServers>,
+}
+
+impl CertVerifier {
+    pub fn new(path: PathBuf) -> Result<Self> {
+        Ok(Self {
+            webpki: rustls::WebPKIVerifier::new(),
+            known: Mutex::new(KnownServers::load(&path)?),
+            path,
+        })
+    }
+
+    /// Record that `name` presents the certificate with fingerprint `fingerprint`
+    fn store(&self, name: &str, fingerprint: &Fingerprint) -> io::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&self.path)?;
+        writeln!(file, "{} {}", name, encode(fingerprint))
+    }
+}
+
+impl rustls::ServerCertVerifier for CertVerifier {
+    fn verify_server_cert(
+        &self,
+        roots: &rustls::RootCertStore,
+        presented_certs: &[rustls::Certificate],
+        dns_name: webpki::DNSNameRef,
+        ocsp_response: &[u8],
+    ) -> Result<rustls::ServerCertVerified, rustls::TLSError> {
+        match self
+            .webpki
+            .verify_server_cert(roots, presented_certs, dns_name, ocsp_response)
+        {
+            Ok(x) => return Ok(x),
+            // Perhaps self-signed, so fall back to pinning
+            Err(rustls::TLSError::WebPKIError(webpki::Error::UnknownIssuer)) => {}
+            Err(e) => return Err(e),
+        }
+        let cert = presented_certs
+            .first()
+            .ok_or(rustls::TLSError::NoCertificatesPresented)?;
+        // Pinning stands in for the issuer, not for the name
+        webpki::EndEntityCert::from(&cert.0)
+            .and_then(|x| x.verify_is_valid_for_dns_name(dns_name))
+            .map_err(rustls::TLSError::WebPKIError)?;
+        let name: &str = dns_name.into();
+        let fingerprint = fingerprint(&cert.0);
+        match self.known.lock().unwrap().check(name, &fingerprint) {
+            Trust::Known => {}
+            Trust::New => {
+                warn!(
+                    "trusting certificate of previously unknown server {} with fingerprint {}",
+                    name,
+                    encode(&fingerprint)
+                );
+                if let Err(e) = self.store(name, &fingerprint) {
+                    error!(
+                        "failed to record certificate: {}: {}",
+                        self.path.display(),
+                        e
+                    );
+                }
+            }
+            Trust::Changed => {
+                return Err(rustls::TLSError::General(format!(
+                    "certificate of {} has changed to one with fingerprint {}; if this is \
+                     expected, remove the server's entry from {}",
+                    name,
+                    encode(&fingerprint),
+                    self.path.display()
+                )));
+            }
+        }
+        Ok(rustls::ServerCertVerified::assertion())
+    }
+}
+
+/// Accepts any certificate, for connecting to the in-process singleplayer server
+///
+/// That server's certificate is regenerated whenever its save is removed, so pinning it would only
+/// lead to spurious failures.
+pub struct SingleplayerVerifier;
+
+impl rustls::ServerCertVerifier for SingleplayerVerifier {
+    fn verify_server_cert(
+        &self,
+        _roots: &rustls::RootCertStore,
+        _presented_certs: &[rustls::Certificate],
+        _dns_name: webpki::DNSNameRef,
+        _ocsp_response: &[u8],
+    ) -> Result<rustls::ServerCertVerified, rustls::TLSError> {
+        Ok(rustls::ServerCertVerified::assertion())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trust_on_first_use() {
+        let mut known = KnownServers::default();
+        let a = fingerprint(b"a");
+        let b = fingerprint(b"b");
+        assert_eq!(known.check("example.com", &a), Trust::New);
+        assert_eq!(known.check("example.com", &a), Trust::Known);
+        assert_eq!(known.check("example.com", &b), Trust::Changed);
+        assert_eq!(known.check("example.org", &b), Trust::New);
+    }
+
+    #[test]
+    fn parse_records() {
+        let a = fingerprint(b"a");
+        let text = format!(
+            "example.com {}\n\nexample.org bogus\nexample.net\n",
+            encode(&a)
+        );
+        let mut known = KnownServers::parse(&text);
+        assert_eq!(known.fingerprints.len(), 1);
+        assert_eq!(known.check("example.com", &a), Trust::Known);
+        assert_eq!(decode(&encode(&a)), Some(a));
+    }
+}
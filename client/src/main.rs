@@ -1,30 +1,54 @@
 mod config;
 mod graphics;
+mod interpolation;
+mod known_servers;
 mod net;
+mod prediction;
+mod replay;
 mod sim;
 
 use config::Config;
 use net::Net;
 use sim::Sim;
 
-use std::sync::Arc;
+use std::{
+    net::{Ipv6Addr, SocketAddr},
+    sync::Arc,
+    thread,
+};
 
+use anyhow::anyhow;
 use ash::extensions::khr;
+use tokio::sync::oneshot;
+use tracing::error;
 
 fn main() {
     // Set up logging
     tracing_subscriber::fmt::init();
 
-    // spawn the server in a new thread
-    std::thread::spawn(|| {
-        if let Err(e) = server::run() {
+    let dirs = directories::ProjectDirs::from("", "", "hypermine").unwrap();
+    let mut config = match Config::load(&dirs) {
+        Ok(x) => x,
+        Err(e) => {
             eprintln!("{:#}", e);
             std::process::exit(1);
         }
-    });
-
-    let dirs = directories::ProjectDirs::from("", "", "hypermine").unwrap();
-    let config = Arc::new(Config::load(&dirs));
+    };
+    let local_server = if config.singleplayer {
+        match start_singleplayer(&config) {
+            Ok((addr, server)) => {
+                config.server = addr.to_string();
+                Some(server)
+            }
+            Err(e) => {
+                eprintln!("{:#}", e);
+                std::process::exit(1);
+            }
+        }
+    } else {
+        None
+    };
+    let config = Arc::new(config);
 
     // Create the OS window
     let window = graphics::EarlyWindow::new();
@@ -34,12 +58,15 @@ fn main() {
         window.required_extension(),
     ]));
 
-    // Kick off networking
-    let net = net::spawn(config.clone());
-    let sim = Sim::new(net, config.clone());
+    // Kick off networking, or play back a recording in its place
+    let net = match config.replay {
+        Some(ref path) => replay::spawn(path.clone(), config.replay_as.clone()),
+        None => net::spawn(config.clone()),
+    };
+    let sim = Sim::new(net);
 
     // Finish creating the window, including the Vulkan resources used to render to it
-    let window = graphics::Window::new(window, core.clone(), config, sim);
+    let window = graphics::Window::new(window, core.clone(), config, sim, local_server);
 
     // Initialize widely-shared graphics resources
     let gfx = Arc::new(
@@ -55,3 +82,66 @@ fn main() {
     // Run the window's event loop
     window.run(gfx);
 }
+
+/// A private server hosted in-process for singleplayer mode
+pub struct LocalServer {
+    shutdown: oneshot::Sender<()>,
+    thread: thread::JoinHandle<()>,
+}
+
+impl LocalServer {
+    /// Disconnect the player, save the world, and wait for the server to exit
+    pub fn shutdown(self) {
+        let _ = self.shutdown.send(());
+        if self.thread.join().is_err() {
+            error!("singleplayer server panicked");
+        }
+    }
+}
+
+/// Host a private server in a new thread, returning the address it's listening on
+fn start_singleplayer(config: &Config) -> anyhow::Result<(SocketAddr, LocalServer)> {
+    let server_cfg = server::Config {
+        server_name: Some(config::SINGLEPLAYER_NAME.into()),
+        listen: SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 0),
+        save_directory: Some(config.data_dir.join("singleplayer")),
+        ..server::Config::default()
+    };
+    let (send, recv) = std::sync::mpsc::channel();
+    let (shutdown_send, shutdown_recv) = oneshot::channel();
+    let thread = thread::spawn(move || {
+        let mut runtime = match tokio::runtime::Runtime::new() {
+            Ok(x) => x,
+            Err(e) => {
+                let _ = send.send(Err(e.into()));
+                return;
+            }
+        };
+        runtime.block_on(async move {
+            let server = match server::Server::new(server_cfg) {
+                Ok(x) => x,
+                Err(e) => {
+                    let _ = send.send(Err(e));
+                    return;
+                }
+            };
+            let _ = send.send(Ok(server.local_addr()));
+            server
+                .run(async {
+                    // Dropping the sender also shuts the server down
+                    let _ = shutdown_recv.await;
+                })
+                .await;
+        });
+    });
+    let addr = recv
+        .recv()
+        .map_err(|_| anyhow!("singleplayer server failed to start"))??;
+    Ok((
+        addr,
+        LocalServer {
+            shutdown: shutdown_send,
+            thread,
+        },
+    ))
+}
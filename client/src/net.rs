@@ -1,16 +1,23 @@
-use std::{sync::Arc, thread};
+use std::{sync::Arc, thread, time::Duration};
 
-use anyhow::{anyhow, Error, Result};
-use futures_util::{StreamExt, TryStreamExt};
+use anyhow::{Error, Result};
+use futures_util::{future, StreamExt, TryStreamExt};
 use tokio::sync::mpsc;
+use tracing::{info, warn};
 
-use common::{codec, proto};
+use common::{
+    codec, proto,
+    session::{self, Disconnect, Session},
+};
 
-use crate::Config;
+use crate::{
+    known_servers::{CertVerifier, SingleplayerVerifier},
+    Config,
+};
 
 pub struct Net {
     pub incoming: mpsc::UnboundedReceiver<Message>,
-    pub outgoing: mpsc::UnboundedSender<proto::Command>,
+    pub outgoing: mpsc::UnboundedSender<proto::ClientMessage>,
     pub thread: thread::JoinHandle<()>,
 }
 
@@ -19,7 +26,7 @@ pub fn spawn(cfg: Arc<Config>) -> Net {
     let (outgoing_send, outgoing_recv) = mpsc::unbounded_channel();
     let thread = thread::spawn(move || {
         if let Err(e) = run(cfg, incoming_send.clone(), outgoing_recv) {
-            let _ = incoming_send.send(Message::ConnectionLost(e));
+            let _ = incoming_send.send(Message::ConnectionLost(Disconnect::new(e)));
         }
     });
     Net {
@@ -34,93 +41,134 @@ pub enum Message {
     Hello(proto::ServerHello),
     Spawns(proto::Spawns),
     StateDelta(proto::StateDelta),
-    ConnectionLost(Error),
+    ConnectionLost(Disconnect),
 }
 
 #[tokio::main(core_threads = 1)]
 async fn run(
     cfg: Arc<Config>,
     incoming: mpsc::UnboundedSender<Message>,
-    outgoing: mpsc::UnboundedReceiver<proto::Command>,
+    mut outgoing: mpsc::UnboundedReceiver<proto::ClientMessage>,
 ) -> Result<()> {
-    let mut endpoint = quinn::Endpoint::builder();
     let mut client_cfg = quinn::ClientConfig::default();
     let tls_cfg = Arc::get_mut(&mut client_cfg.crypto).unwrap();
-    tls_cfg
-        .dangerous()
-        .set_certificate_verifier(Arc::new(AcceptAnyCert));
-    endpoint.default_client_config(client_cfg);
-    let (endpoint, _) = endpoint.bind(&"[::]:0".parse().unwrap())?;
+    tls_cfg.root_store = match rustls_native_certs::load_native_certs() {
+        Ok(x) => x,
+        Err((partial, e)) => {
+            warn!("failed to load system root certificates: {}", e);
+            partial.unwrap_or_else(rustls::RootCertStore::empty)
+        }
+    };
+    if cfg.singleplayer {
+        tls_cfg
+            .dangerous()
+            .set_certificate_verifier(Arc::new(SingleplayerVerifier));
+    } else {
+        let verifier = CertVerifier::new(cfg.data_dir.join("known_servers"))?;
+        tls_cfg
+            .dangerous()
+            .set_certificate_verifier(Arc::new(verifier));
+    }
 
-    let result = inner(cfg, incoming, outgoing, endpoint.clone()).await;
-    endpoint.wait_idle().await;
-    result
+    let mut delay = MIN_RECONNECT_DELAY;
+    loop {
+        let error = match connect(&cfg, client_cfg.clone()).await {
+            Ok(session) => {
+                delay = MIN_RECONNECT_DELAY;
+                // Commands issued during a previous connection are meaningless now
+                while outgoing.try_recv().is_ok() {}
+                match drive(session, &incoming, &mut outgoing).await {
+                    // The simulation has gone away
+                    Ok(()) => return Ok(()),
+                    Err(e) => e,
+                }
+            }
+            Err(e) => e,
+        };
+        let disconnect = Disconnect::new(error);
+        let reconnect = disconnect.should_reconnect();
+        if incoming.send(Message::ConnectionLost(disconnect)).is_err() || !reconnect {
+            return Ok(());
+        }
+        info!("reconnecting in {}s", delay.as_secs());
+        tokio::time::delay_for(delay).await;
+        delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+    }
 }
 
-async fn inner(
-    cfg: Arc<Config>,
-    incoming: mpsc::UnboundedSender<Message>,
-    outgoing: mpsc::UnboundedReceiver<proto::Command>,
-    endpoint: quinn::Endpoint,
-) -> Result<()> {
-    let quinn::NewConnection {
-        connection,
-        mut uni_streams,
-        ..
-    } = endpoint
-        .connect(&"[::1]:1234".parse().unwrap(), "localhost")
-        .unwrap()
-        .await?;
-
-    // Open the first stream for our hello message
-    let clienthello_stream = connection.open_uni().await?;
-    // Start sending commands asynchronously
-    tokio::spawn(handle_outgoing(outgoing, connection));
-    // Actually send the hello message
-    codec::send_whole(
-        clienthello_stream,
+async fn connect(cfg: &Config, client_cfg: quinn::ClientConfig) -> Result<Session> {
+    session::connect(
+        &cfg.server,
+        &cfg.server_name,
+        client_cfg,
         &proto::ClientHello {
+            protocol_version: proto::PROTOCOL_VERSION,
+            capabilities: proto::Capabilities::SUPPORTED,
             name: (*cfg.name).into(),
+            credential: cfg.credential,
         },
     )
-    .await?;
-
-    let mut ordered = uni_streams.next().await.unwrap()?;
-    // Handle unordered messages
-    tokio::spawn(handle_unordered(incoming.clone(), uni_streams));
-
-    // Receive the server's hello message
-    let hello = codec::recv::<proto::ServerHello>(&mut ordered)
-        .await?
-        .ok_or_else(|| anyhow!("ordered stream closed unexpectedly"))?;
-    // Forward it on
-    incoming.send(Message::Hello(hello)).unwrap();
+    .await
+}
 
-    // Receive ordered messages from the server
-    loop {
-        let spawns = codec::recv::<proto::Spawns>(&mut ordered)
-            .await?
-            .ok_or_else(|| anyhow!("ordered stream closed unexpectedly"))?;
-        incoming.send(Message::Spawns(spawns)).unwrap();
+/// Exchange messages with the server until the connection is lost
+///
+/// Returns `Ok` if the simulation stops listening.
+async fn drive(
+    session: Session,
+    incoming: &mpsc::UnboundedSender<Message>,
+    outgoing: &mut mpsc::UnboundedReceiver<proto::ClientMessage>,
+) -> Result<()> {
+    let Session {
+        endpoint,
+        connection,
+        uni_streams,
+        mut ordered,
+        hello,
+    } = session;
+    if incoming.send(Message::Hello(hello)).is_err() {
+        return Ok(());
     }
+    let handle_ordered = async {
+        // The server only finishes the ordered stream as it closes the connection, so the reason
+        // for the close will arrive on the unordered streams
+        while let Some(spawns) = codec::recv::<proto::Spawns>(&mut ordered).await? {
+            if incoming.send(Message::Spawns(spawns)).is_err() {
+                break;
+            }
+        }
+        Ok::<_, Error>(())
+    };
+    // Run everything in this task, so that nothing from this connection can reach the simulation
+    // after it's told the connection was lost
+    let result = future::try_join3(
+        handle_ordered,
+        handle_unordered(incoming, uni_streams),
+        handle_outgoing(outgoing, connection.clone()),
+    )
+    .await;
+    // Let the server know promptly if we're the ones giving up
+    connection.close(proto::DisconnectReason::Closed.code().into(), b"");
+    endpoint.wait_idle().await;
+    result.map(|_| ())
 }
 
 /// Send commands to the server
 async fn handle_outgoing(
-    mut outgoing: mpsc::UnboundedReceiver<proto::Command>,
+    outgoing: &mut mpsc::UnboundedReceiver<proto::ClientMessage>,
     connection: quinn::Connection,
 ) -> Result<()> {
-    while let Some(cmd) = outgoing.recv().await {
+    while let Some(msg) = outgoing.recv().await {
         let stream = connection.open_uni().await?;
         // TODO: Don't silently die on parse errors
-        codec::send_whole(stream, &cmd).await?;
+        codec::send_whole(stream, &msg).await?;
     }
     Ok(())
 }
 
 /// Receive unordered messages from the server
 async fn handle_unordered(
-    incoming: mpsc::UnboundedSender<Message>,
+    incoming: &mpsc::UnboundedSender<Message>,
     uni_streams: quinn::IncomingUniStreams,
 ) -> Result<()> {
     let mut msgs = uni_streams
@@ -131,21 +179,13 @@ async fn handle_unordered(
         .buffer_unordered(128);
     // TODO: Don't silently die on parse errors
     while let Some(msg) = msgs.try_next().await? {
-        incoming.send(Message::StateDelta(msg)).unwrap();
+        if incoming.send(Message::StateDelta(msg)).is_err() {
+            break;
+        }
     }
     Ok(())
 }
 
-struct AcceptAnyCert;
-
-impl rustls::ServerCertVerifier for AcceptAnyCert {
-    fn verify_server_cert(
-        &self,
-        _roots: &rustls::RootCertStore,
-        _presented_certs: &[rustls::Certificate],
-        _dns_name: webpki::DNSNameRef,
-        _ocsp_response: &[u8],
-    ) -> Result<rustls::ServerCertVerified, rustls::TLSError> {
-        Ok(rustls::ServerCertVerified::assertion())
-    }
-}
+/// Delay before the first attempt to reconnect, doubled after each failure
+const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
@@ -0,0 +1,176 @@
+use std::collections::VecDeque;
+
+use common::{
+    graph::Graph, movement, proto::Position, world::VoxelData, worldgen::NodeState, Step,
+};
+
+/// Predicts the motion of the local character ahead of the server
+///
+/// Every command sent to the server is applied locally at once and logged. When the server reports
+/// the character's state as of some command, that state replaces the prediction, and the commands
+/// the server hasn't applied yet are replayed on top of it.
+pub struct PredictedMotion {
+    /// Commands the server hasn't acknowledged, oldest first
+    log: VecDeque<(Step, movement::Input)>,
+    /// Step of the most recent command the server has acknowledged
+    acknowledged: Step,
+    /// Step to be assigned to the next command
+    next_step: Step,
+    /// Duration of a step, in seconds
+    dt: f32,
+    position: Position,
+    state: movement::State,
+}
+
+impl PredictedMotion {
+    pub fn new(position: Position, dt: f32) -> Self {
+        Self {
+            log: VecDeque::new(),
+            acknowledged: 0,
+            next_step: 1,
+            dt,
+            position,
+            state: movement::State::default(),
+        }
+    }
+
+    /// Apply `input` for one step, returning the step to send it to the server as
+    pub fn push<C>(
+        &mut self,
+        graph: &Graph<NodeState, C>,
+        voxels: impl Fn(&C) -> &VoxelData,
+        input: &movement::Input,
+    ) -> Step {
+        let step = self.next_step;
+        self.next_step += 1;
+        if self.log.len() == MAX_LOG_LEN {
+            self.log.pop_front();
+        }
+        self.log.push_back((step, *input));
+        movement::step(
+            graph,
+            voxels,
+            &mut self.position,
+            &mut self.state,
+            input,
+            self.dt,
+        );
+        step
+    }
+
+    /// Correct the prediction given the server's view of the character after it applied the
+    /// command sent as `latest_command`
+    pub fn reconcile<C>(
+        &mut self,
+        graph: &Graph<NodeState, C>,
+        voxels: impl Fn(&C) -> &VoxelData,
+        latest_command: Step,
+        position: Position,
+        state: movement::State,
+    ) {
+        if latest_command < self.acknowledged {
+            // Superseded by a report we've already seen
+            return;
+        }
+        self.acknowledged = latest_command;
+        while let Some(&(step, _)) = self.log.front() {
+            if step > latest_command {
+                break;
+            }
+            self.log.pop_front();
+        }
+        self.position = position;
+        self.state = state;
+        for (_, input) in &self.log {
+            movement::step(
+                graph,
+                &voxels,
+                &mut self.position,
+                &mut self.state,
+                input,
+                self.dt,
+            );
+        }
+    }
+
+    /// Predicted position of the local character
+    pub fn position(&self) -> &Position {
+        &self.position
+    }
+}
+
+/// Number of unacknowledged commands retained for replay
+///
+/// Bounds the cost of reconciliation when the server stops responding.
+const MAX_LOG_LEN: usize = 64;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::{graph::NodeId, math};
+
+    const DT: f32 = 0.1;
+
+    /// A graph of empty chunks without generation state, where characters fly freely
+    fn empty_graph() -> Graph<NodeState, VoxelData> {
+        let mut graph = Graph::new();
+        graph.ensure_nearby(NodeId::ROOT, 2);
+        let nodes = std::iter::once(NodeId::ROOT)
+            .chain(
+                graph
+                    .tree()
+                    .map(|(side, parent)| graph.neighbor(parent, side).unwrap()),
+            )
+            .collect::<Vec<_>>();
+        for node in nodes {
+            for vertex in graph.cubes_at(node) {
+                *graph.get_cube_mut(node, vertex) = Some(VoxelData::Empty);
+            }
+        }
+        graph
+    }
+
+    fn origin() -> Position {
+        Position {
+            node: NodeId::ROOT,
+            local: na::Matrix4::identity(),
+        }
+    }
+
+    fn forward() -> movement::Input {
+        movement::Input {
+            velocity: -na::Vector3::z(),
+            ..movement::Input::default()
+        }
+    }
+
+    fn distance(position: &Position) -> f32 {
+        assert_eq!(position.node, NodeId::ROOT);
+        math::distance(&math::origin(), &(position.local * math::origin()))
+    }
+
+    #[test]
+    fn replay_unacknowledged() {
+        let graph = empty_graph();
+        let mut prediction = PredictedMotion::new(origin(), DT);
+        let steps = (0..5)
+            .map(|_| prediction.push(&graph, |x| x, &forward()))
+            .collect::<Vec<_>>();
+        assert_eq!(steps, [1, 2, 3, 4, 5]);
+        assert!((distance(prediction.position()) - 0.5).abs() < 1e-4);
+
+        // The server applied two commands, but didn't let us move
+        prediction.reconcile(&graph, |x| x, 2, origin(), movement::State::default());
+        assert!((distance(prediction.position()) - 0.3).abs() < 1e-4);
+
+        // Stale reports are ignored
+        let mut moved = origin();
+        moved.local = math::translate_along(&na::Vector3::x_axis(), 0.1);
+        prediction.reconcile(&graph, |x| x, 1, moved, movement::State::default());
+        assert!((distance(prediction.position()) - 0.3).abs() < 1e-4);
+
+        // Once everything is acknowledged, the server's view is adopted as-is
+        prediction.reconcile(&graph, |x| x, 5, moved, movement::State::default());
+        assert_eq!(prediction.position().local, moved.local);
+    }
+}
@@ -0,0 +1,92 @@
+//! Playback of a server's recording in place of a live connection
+//!
+//! Messages sent to one player during the recorded session are delivered at the pace they were
+//! recorded, and commands are discarded. Local input still moves the view, but it's corrected to
+//! the recorded character's position by each state delta.
+
+use std::{
+    fs,
+    io::BufReader,
+    path::{Path, PathBuf},
+    thread,
+    time::Instant,
+};
+
+use anyhow::{anyhow, Context, Result};
+use tokio::sync::mpsc;
+use tracing::info;
+
+use common::{
+    recording::{self, Event},
+    session::Disconnect,
+};
+
+use crate::{net::Message, Net};
+
+pub fn spawn(path: PathBuf, name: Option<String>) -> Net {
+    let (incoming_send, incoming_recv) = mpsc::unbounded_channel();
+    // Commands go nowhere
+    let (outgoing_send, _) = mpsc::unbounded_channel();
+    let thread = thread::spawn(move || {
+        let result = run(&path, name.as_deref(), &incoming_send)
+            .with_context(|| format!("playing back {}", path.display()));
+        if let Err(e) = result {
+            let _ = incoming_send.send(Message::ConnectionLost(Disconnect::new(e)));
+        }
+    });
+    Net {
+        incoming: incoming_recv,
+        outgoing: outgoing_send,
+        thread,
+    }
+}
+
+/// Deliver the first session in the recording at `path` that belongs to `name`, or to anyone if
+/// `name` is `None`
+fn run(path: &Path, name: Option<&str>, incoming: &mpsc::UnboundedSender<Message>) -> Result<()> {
+    let file = fs::File::open(path)?;
+    let start = Instant::now();
+    // Character whose session we're playing back, and when it began
+    let mut session = None;
+    for entry in recording::Reader::new(BufReader::new(file))? {
+        let entry = entry?;
+        let msg = match (session, entry.event) {
+            (
+                None,
+                Event::Hello {
+                    name: player,
+                    hello,
+                },
+            ) if name.map_or(true, |x| x == player) => {
+                info!(%player, "playing back session");
+                session = Some((entry.character, entry.time));
+                Message::Hello(hello)
+            }
+            (Some((character, _)), event) if entry.character == character => match event {
+                Event::Spawns(x) => Message::Spawns(x),
+                Event::StateDelta(x) => Message::StateDelta(x),
+                Event::Lost => break,
+                Event::Hello { .. } | Event::Command(_) => continue,
+            },
+            _ => continue,
+        };
+        let (_, begin) = session.unwrap();
+        let due = start + (entry.time - begin);
+        let now = Instant::now();
+        if due > now {
+            thread::sleep(due - now);
+        }
+        if incoming.send(msg).is_err() {
+            // The simulation has gone away
+            return Ok(());
+        }
+    }
+    if session.is_none() {
+        return Err(match name {
+            Some(name) => anyhow!("no session for {} recorded", name),
+            None => anyhow!("no sessions recorded"),
+        });
+    }
+    info!("playback complete");
+    Ok(())
+}
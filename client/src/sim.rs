@@ -1,56 +1,113 @@
-use std::{sync::Arc, time::Duration};
+use std::{collections::VecDeque, time::Duration};
 
 use fxhash::FxHashMap;
 use hecs::Entity;
-use tracing::{error, trace};
+use tracing::{debug, error, info, trace};
 
-use crate::{graphics::lru_table::SlotId, net, Config, Net};
+use crate::{
+    graphics::lru_table::SlotId, interpolation::PositionHistory, net, prediction::PredictedMotion,
+    Net,
+};
 use common::{
+    cursor::Cursor,
     dodeca,
     graph::{Graph, NodeId},
-    proto::{self, Command, Position},
-    world::{Material, SUBDIVISION_FACTOR},
+    math, movement,
+    proto::{self, BlockUpdate, ClientMessage, Command, MovementMode, Position},
+    raycast::raycast,
+    world::{voxel_at, ChunkId, Material, VoxelData, BLOCK_REACH},
+    worldgen::{ChunkParams, NodeState},
     EntityId, Step,
 };
 
 /// Game state
 pub struct Sim {
-    cfg: Arc<Config>,
     net: Net,
 
     // World state
     entity_ids: FxHashMap<EntityId, Entity>,
     world: hecs::World,
-    pub graph: Graph<bool, Cube>,
+    pub graph: Graph<NodeState, Cube>,
+    /// Number of times `graph` has been discarded
+    graph_epoch: u64,
+    /// Our IDs for the nodes we know of, keyed by the server's
+    node_ids: FxHashMap<NodeId, NodeId>,
+    /// The server's IDs for the nodes we know of, keyed by ours
+    server_node_ids: FxHashMap<NodeId, NodeId>,
+    /// Modifications to chunks that haven't been generated yet
+    pending_block_updates: FxHashMap<ChunkId, Vec<BlockUpdate>>,
     local_character: Option<EntityId>,
     orientation: na::UnitQuaternion<f32>,
     step: Option<Step>,
+    /// Estimate of the step the server is currently on, advanced smoothly between updates
+    clock: Option<f64>,
+    /// Number of steps simulated per second, as dictated by the server
+    rate: Option<u16>,
+    prediction: Option<PredictedMotion>,
+    /// Recent chat messages, oldest first
+    chat_log: VecDeque<proto::ChatMessage>,
 
     // Input state
-    since_input_sent: Duration,
+    /// Time elapsed since the local character was last stepped
+    since_step: Duration,
     velocity: na::Vector3<f32>,
+    movement_mode: MovementMode,
+    jump: bool,
+    crouch: bool,
 }
 
 impl Sim {
-    pub fn new(net: Net, cfg: Arc<Config>) -> Self {
-        let mut result = Self {
-            cfg,
+    pub fn new(net: Net) -> Self {
+        let root = std::iter::once((NodeId::ROOT, NodeId::ROOT));
+        Self {
             net,
 
             graph: Graph::new(),
+            graph_epoch: 0,
+            node_ids: root.clone().collect(),
+            server_node_ids: root.collect(),
+            pending_block_updates: FxHashMap::default(),
             entity_ids: FxHashMap::default(),
             world: hecs::World::new(),
             local_character: None,
             orientation: na::one(),
             step: None,
+            clock: None,
+            rate: None,
+            prediction: None,
+            chat_log: VecDeque::new(),
 
-            since_input_sent: Duration::new(0, 0),
+            since_step: Duration::new(0, 0),
             velocity: na::zero(),
-        };
+            movement_mode: MovementMode::Fly,
+            jump: false,
+            crouch: false,
+        }
+    }
 
-        result.populate_node(NodeId::ROOT);
+    /// Distinguishes `graph` from those discarded previously, so that references to nodes can be
+    /// invalidated
+    pub fn graph_epoch(&self) -> u64 {
+        self.graph_epoch
+    }
 
-        result
+    /// Forget everything learned from the server
+    fn reset(&mut self) {
+        let root = std::iter::once((NodeId::ROOT, NodeId::ROOT));
+        self.entity_ids.clear();
+        self.world = hecs::World::new();
+        self.graph = Graph::new();
+        self.graph_epoch += 1;
+        self.node_ids = root.clone().collect();
+        self.server_node_ids = root.collect();
+        self.pending_block_updates.clear();
+        self.local_character = None;
+        self.step = None;
+        self.clock = None;
+        self.rate = None;
+        self.prediction = None;
+        self.since_step = Duration::new(0, 0);
+        // The chat log is kept, so that conversations survive reconnecting
     }
 
     pub fn rotate(&mut self, delta: &na::UnitQuaternion<f32>) {
@@ -61,15 +118,109 @@ impl Sim {
         self.velocity = v;
     }
 
+    /// Switch between flying and walking
+    pub fn toggle_movement_mode(&mut self) {
+        self.movement_mode = match self.movement_mode {
+            MovementMode::Fly => MovementMode::Walk,
+            MovementMode::Walk => MovementMode::Fly,
+        };
+    }
+
+    pub fn jump(&mut self, jump: bool) {
+        self.jump = jump;
+    }
+
+    pub fn crouch(&mut self, crouch: bool) {
+        self.crouch = crouch;
+    }
+
+    /// Ask the server to remove the block in view, if any is within reach
+    pub fn break_block(&mut self) {
+        if let Some((chunk_id, coords)) = self.target_block(false) {
+            self.send_block_update(chunk_id, coords, Material::Void);
+        }
+    }
+
+    /// Ask the server to fill the empty space in front of the block in view, if any is within
+    /// reach
+    pub fn place_block(&mut self) {
+        if let Some((chunk_id, coords)) = self.target_block(true) {
+            self.send_block_update(chunk_id, coords, Material::Stone);
+        }
+    }
+
+    /// Locate the solid voxel the view is pointed at, or the one just in front of it if `in_front`
+    fn target_block(&self, in_front: bool) -> Option<(ChunkId, [usize; 3])> {
+        if self.prediction.is_none() {
+            // Not spawned yet
+            return None;
+        }
+        let view = self.view();
+        let forward = -na::Vector3::z_axis();
+        let hit = raycast(&self.graph, |x| &x.voxels, &view, &forward, BLOCK_REACH)?;
+        if !in_front {
+            return Some((hit.chunk, hit.voxel));
+        }
+        if hit.face.is_none() {
+            // The view begins inside the block, so there's no empty space in front of it
+            return None;
+        }
+        // The ray passed through the neighboring voxel just before striking the block
+        let distance = (hit.distance - 1e-3).max(0.0) as f32;
+        let p = (view.local * math::translate_along(&forward, distance) * math::origin())
+            .map(|x| x as f64);
+        let (node, transform) = self.graph.normalize_point(view.node, &p);
+        let p = transform * p;
+        let (node, vertex, transform) = Cursor::from_vertex(node, dodeca::Vertex::containing(&p))
+            .canonicalize_with_transform(&self.graph)?;
+        let p = vertex.node_to_cube() * transform * p;
+        Some((ChunkId::new(node, vertex), voxel_at(&(p.xyz() / p.w))))
+    }
+
+    fn send_block_update(&mut self, chunk_id: ChunkId, coords: [usize; 3], new_material: Material) {
+        let node = match self.server_node_ids.get(&chunk_id.node) {
+            Some(&x) => x,
+            None => return,
+        };
+        let update = BlockUpdate {
+            chunk_id: ChunkId::new(node, chunk_id.vertex),
+            coords: [coords[0] as u8, coords[1] as u8, coords[2] as u8],
+            new_material,
+        };
+        debug!(?update, "requesting block update");
+        // The server will describe the outcome in a future `Spawns`
+        let _ = self.net.outgoing.send(ClientMessage::BlockUpdate(update));
+    }
+
+    /// Send a chat message to every player
+    pub fn chat(&mut self, text: String) {
+        let _ = self.net.outgoing.send(ClientMessage::Chat(text));
+    }
+
+    /// Recently received chat messages, oldest first
+    pub fn chat_log(&self) -> &VecDeque<proto::ChatMessage> {
+        &self.chat_log
+    }
+
     pub fn step(&mut self, dt: Duration) {
         while let Ok(msg) = self.net.incoming.try_recv() {
             self.handle_net(msg);
         }
 
-        self.since_input_sent += dt;
-        if self.since_input_sent > Duration::from_secs(1) / (self.cfg.input_send_rate as u32) {
-            self.send_input();
-            self.since_input_sent = Duration::new(0, 0);
+        if let (Some(clock), Some(rate)) = (self.clock.as_mut(), self.rate) {
+            *clock += dt.as_secs_f64() * rate as f64;
+        }
+        self.interpolate();
+
+        if let Some(rate) = self.rate {
+            // Step the local character at the same rate as the server, so each command sent
+            // corresponds to exactly one step there
+            let interval = Duration::from_secs(1) / rate as u32;
+            self.since_step += dt;
+            while self.since_step >= interval {
+                self.since_step -= interval;
+                self.step_local_character();
+            }
         }
     }
 
@@ -77,31 +228,120 @@ impl Sim {
         use net::Message::*;
         match msg {
             ConnectionLost(e) => {
-                error!("connection lost: {}", e);
+                error!("disconnected: {}", e);
+                // The next connection will describe the world afresh
+                self.reset();
             }
             Hello(msg) => {
                 self.local_character = Some(msg.character);
+                self.rate = Some(msg.rate);
+                // The ordered stream guarantees this precedes any nodes
+                *self.graph.get_mut(NodeId::ROOT) = Some(NodeState::root(msg.world_seed));
             }
             Spawns(msg) => self.handle_spawns(msg),
             StateDelta(msg) => {
-                self.step = self.step.max(Some(msg.step));
+                // Deltas arrive on independent streams, so they may be reordered. Each carries only
+                // what changed, so stale deltas still hold the latest state of some entities.
+                let stale = Some(msg.step) < self.step;
+                if !stale {
+                    self.step = Some(msg.step);
+                    // We can't be behind a step we've heard about, and probably aren't much ahead
+                    let step = msg.step as f64;
+                    self.clock = Some(self.clock.map_or(step, |x| na::clamp(x, step, step + 1.0)));
+                }
                 for &(id, new_pos) in &msg.positions {
+                    // Entities and nodes entering view are described on a separate stream, which
+                    // deltas may overtake
+                    let new_pos = match self.localize(new_pos.unpack()) {
+                        None => {
+                            debug!(%id, "position update in unknown node");
+                            continue;
+                        }
+                        Some(x) => x,
+                    };
                     match self.entity_ids.get(&id) {
-                        None => error!(%id, "position update for unknown entity"),
-                        Some(&entity) => match self.world.get_mut::<Position>(entity) {
-                            Ok(mut pos) => {
-                                *pos = new_pos;
+                        None => debug!(%id, "position update for unknown entity"),
+                        Some(&entity) => match self.world.get_mut::<PositionHistory>(entity) {
+                            Ok(mut history) => {
+                                history.push(msg.step, new_pos);
                             }
                             Err(e) => error!(%id, "position update for unpositioned entity: {}", e),
                         },
                     }
                 }
+                if stale {
+                    trace!(step = msg.step, "not reconciling with stale state delta");
+                } else {
+                    self.reconcile(&msg);
+                }
             }
         }
     }
 
+    /// Display entities slightly in the past, so their motion can be interpolated between updates
+    fn interpolate(&mut self) {
+        let time = match self.clock {
+            Some(x) => x - INTERPOLATION_DELAY,
+            None => return,
+        };
+        for (_, (pos, history)) in self
+            .world
+            .query::<(&mut Position, &PositionHistory)>()
+            .iter()
+        {
+            *pos = history.sample(&self.graph, time);
+        }
+    }
+
+    /// Correct the predicted motion of the local character according to the server
+    fn reconcile(&mut self, msg: &proto::StateDelta) {
+        let id = match self.local_character {
+            Some(x) => x,
+            None => return,
+        };
+        let position = msg
+            .positions
+            .iter()
+            .find(|&&(x, _)| x == id)
+            .and_then(|&(_, position)| self.localize(position.unpack()));
+        if let (Some(prediction), Some(position)) = (self.prediction.as_mut(), position) {
+            prediction.reconcile(
+                &self.graph,
+                |x| &x.voxels,
+                msg.latest_command,
+                position,
+                msg.motion,
+            );
+        }
+    }
+
+    /// Translate a position from the server's numbering of nodes to ours
+    fn localize(&self, position: Position) -> Option<Position> {
+        Some(Position {
+            node: *self.node_ids.get(&position.node)?,
+            local: position.local,
+        })
+    }
+
     fn handle_spawns(&mut self, msg: proto::Spawns) {
         self.step = self.step.max(Some(msg.step));
+        if !msg.nodes.is_empty() {
+            trace!(count = msg.nodes.len(), "adding nodes");
+        }
+        for node in &msg.nodes {
+            // Nodes arrive in order of length, so their shorter neighbors are already known
+            let parent = match self.node_ids.get(&node.parent) {
+                Some(&x) => x,
+                None => {
+                    error!(parent = ?node.parent, "node added to unknown parent");
+                    continue;
+                }
+            };
+            let local = self.graph.insert_child(parent, node.side);
+            self.node_ids.insert(node.id, local);
+            self.server_node_ids.insert(local, node.id);
+        }
+        self.populate_fresh_nodes();
         let mut builder = hecs::EntityBuilder::new();
         for &(id, ref components) in &msg.spawns {
             trace!(%id, "spawning entity");
@@ -109,9 +349,26 @@ impl Sim {
             for component in components {
                 use common::proto::Component::*;
                 match *component {
-                    Character(_) => {}
+                    Character(ref x) => {
+                        if Some(id) == self.local_character {
+                            // Pick up where we left off, if the server remembers our character
+                            self.orientation = x.orientation;
+                        }
+                    }
                     Position(x) => {
+                        let x = match self.localize(x) {
+                            Some(x) => x,
+                            None => {
+                                error!(%id, node = ?x.node, "entity spawned in unknown node");
+                                continue;
+                            }
+                        };
                         builder.add(x);
+                        builder.add(PositionHistory::new(msg.step, x));
+                        if Some(id) == self.local_character {
+                            let dt = 1.0 / self.rate.unwrap() as f32;
+                            self.prediction = Some(PredictedMotion::new(x, dt));
+                        }
                     }
                 }
             }
@@ -127,31 +384,75 @@ impl Sim {
                 None => error!(%id, "despawned unknown entity"),
             }
         }
-        if !msg.nodes.is_empty() {
-            trace!(count = msg.nodes.len(), "adding nodes");
+        for update in &msg.block_updates {
+            let mut update = *update;
+            update.chunk_id.node = match self.node_ids.get(&update.chunk_id.node) {
+                Some(&x) => x,
+                None => {
+                    error!(chunk = ?update.chunk_id, "block update in unknown node");
+                    continue;
+                }
+            };
+            self.apply_block_update(update);
         }
-        for node in &msg.nodes {
-            self.graph.insert_child(node.parent, node.side);
+        for chat in msg.chat {
+            info!(sender = %chat.sender, "{}: {}", chat.name, chat.text);
+            if self.chat_log.len() == MAX_CHAT_LOG_LEN {
+                self.chat_log.pop_front();
+            }
+            self.chat_log.push_back(chat);
         }
-        self.populate_fresh_nodes();
     }
 
-    fn send_input(&mut self) {
-        if let Some(&entity) = self.local_character.and_then(|id| self.entity_ids.get(&id)) {
-            let pos = *self.world.get::<Position>(entity).unwrap();
-            // Any failure here will be better handled in ConnectionLost above on the next call
-            let _ = self.net.outgoing.send(Command {
-                step: self.step.unwrap(),
-                node: pos.node,
-                orientation: self.orientation,
-                velocity: self.orientation * self.velocity,
-            });
-        }
+    fn apply_block_update(&mut self, update: BlockUpdate) {
+        let chunk = match *self
+            .graph
+            .get_cube_mut(update.chunk_id.node, update.chunk_id.vertex)
+        {
+            None => {
+                self.pending_block_updates
+                    .entry(update.chunk_id)
+                    .or_default()
+                    .push(update);
+                return;
+            }
+            Some(ref mut x) => x,
+        };
+        let [x, y, z] = update.coords;
+        chunk
+            .voxels
+            .set([x as usize, y as usize, z as usize], update.new_material);
+        chunk.dirty = true;
+    }
+
+    /// Apply the current input to the local character, and send it to the server
+    fn step_local_character(&mut self) {
+        let prediction = match self.prediction {
+            Some(ref mut x) => x,
+            None => return,
+        };
+        let input = movement::Input {
+            velocity: self.orientation * self.velocity,
+            mode: self.movement_mode,
+            jump: self.jump,
+            crouch: self.crouch,
+        };
+        let step = prediction.push(&self.graph, |x| &x.voxels, &input);
+        // Any failure here will be better handled in ConnectionLost above on the next call
+        let _ = self.net.outgoing.send(ClientMessage::Command(Command {
+            step,
+            node: self.server_node_ids[&prediction.position().node],
+            orientation: self.orientation,
+            velocity: input.velocity,
+            mode: input.mode,
+            jump: input.jump,
+            crouch: input.crouch,
+        }));
     }
 
     pub fn view(&self) -> Position {
-        if let Some(&entity) = self.local_character.and_then(|id| self.entity_ids.get(&id)) {
-            let mut pos = *self.world.get::<Position>(entity).unwrap();
+        if let Some(ref prediction) = self.prediction {
+            let mut pos = *prediction.position();
             pos.local *= self.orientation.to_homogeneous();
             pos
         } else {
@@ -176,81 +477,51 @@ impl Sim {
         for &node in &fresh {
             self.populate_node(node);
         }
-        for &node in &fresh {
-            for cube in self.graph.cubes_at(node) {
-                self.populate_cube(node, cube);
+        // A chunk can be generated once every node at its corners is known
+        for &fresh_node in &fresh {
+            let cubes = self.graph.incident_cubes(fresh_node).collect::<Vec<_>>();
+            for (node, cube) in cubes {
+                if self.graph.get_cube(node, cube).is_none() {
+                    self.populate_cube(node, cube);
+                }
             }
         }
-        self.graph.clear_fresh();
     }
 
     fn populate_node(&mut self, node: NodeId) {
-        use common::dodeca::Side;
-
-        *self.graph.get_mut(node) = Some(match self.graph.parent(node) {
-            None => true,
-            Some(x) => {
-                let parent_solid = self
-                    .graph
-                    .get(self.graph.neighbor(node, x).unwrap())
-                    .unwrap();
-                if x == Side::A {
-                    !parent_solid
-                } else {
-                    parent_solid
-                }
-            }
-        });
+        *self.graph.get_mut(node) = Some(NodeState::from_parent(&self.graph, node));
     }
 
     fn populate_cube(&mut self, node: NodeId, cube: dodeca::Vertex) {
-        let contains_border = cube.canonical_sides().iter().any(|&x| x == dodeca::Side::A);
-
-        let voxels = if contains_border {
-            let mut data = (0..(SUBDIVISION_FACTOR + 2).pow(3))
-                .map(|_| Material::Void)
-                .collect::<Vec<_>>()
-                .into_boxed_slice();
-
-            const MAGIC: u32 = 1_000_081;
-            let mut rd = ((cube as u32) + 20 * u32::from(node)) % MAGIC; // Pseudorandom value to fill chunk with
-            const GAP: usize = 0;
-            const XGAP: usize = (SUBDIVISION_FACTOR - 1) / 2; // dodeca::Side::A will always correspond to the x coordinate, so let`s flatten it in this direction
-            for z in GAP..(SUBDIVISION_FACTOR - GAP) {
-                for y in GAP..(SUBDIVISION_FACTOR - GAP) {
-                    for x in XGAP..(SUBDIVISION_FACTOR - XGAP) {
-                        rd = (37 * rd + 1) % MAGIC;
-                        data[(x + 1)
-                            + (y + 1) * (SUBDIVISION_FACTOR + 2)
-                            + (z + 1) * (SUBDIVISION_FACTOR + 2).pow(2)] = if rd % 4 == 1 {
-                            Material::Stone
-                        } else if rd % 4 == 2 {
-                            Material::Dirt
-                        } else if rd % 4 == 3 {
-                            Material::Sand
-                        } else {
-                            Material::Void
-                        };
-                    }
-                }
+        let chunk_id = ChunkId::new(node, cube);
+        if let Some(params) = ChunkParams::new(&self.graph, chunk_id) {
+            *self.graph.get_cube_mut(node, cube) = Some(Cube {
+                surface: None,
+                voxels: params.generate(),
+                dirty: false,
+            });
+            for update in self
+                .pending_block_updates
+                .remove(&chunk_id)
+                .unwrap_or_default()
+            {
+                self.apply_block_update(update);
             }
-            VoxelData::Dense(data)
-        } else {
-            VoxelData::Empty
-        };
-        *self.graph.get_cube_mut(node, cube) = Some(Cube {
-            surface: None,
-            voxels,
-        });
+        }
     }
 }
 
+/// Number of steps behind the server at which entities are displayed
+///
+/// Large enough that the displayed time is usually bracketed by two updates, even if one is late.
+const INTERPOLATION_DELAY: f64 = 2.0;
+
+/// Number of chat messages remembered
+const MAX_CHAT_LOG_LEN: usize = 100;
+
 pub struct Cube {
     pub surface: Option<SlotId>,
     pub voxels: VoxelData,
-}
-
-pub enum VoxelData {
-    Empty,
-    Dense(Box<[Material]>),
+    /// Whether `voxels` has changed since `surface` was extracted
+    pub dirty: bool,
 }
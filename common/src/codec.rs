@@ -1,17 +1,27 @@
-use std::convert::TryFrom;
+use std::{convert::TryFrom, mem};
 
 use anyhow::{anyhow, bail, Result};
 use serde::{de::DeserializeOwned, Serialize};
 
 pub async fn send<T: Serialize + ?Sized>(stream: &mut quinn::SendStream, msg: &T) -> Result<()> {
+    stream.write_all(&frame(msg)?).await?;
+    Ok(())
+}
+
+/// Encode `msg` prefixed by its length, as sent by `send`
+fn frame<T: Serialize + ?Sized>(msg: &T) -> Result<Vec<u8>> {
     let mut buf = Vec::new();
     let tag = u16::try_from(bincode::serialized_size(msg).unwrap())
         .map_err(|_| anyhow!("message too long to send"))?
         .to_le_bytes();
     buf.extend_from_slice(&tag);
     bincode::serialize_into(&mut buf, msg).unwrap();
-    stream.write_all(&buf).await?;
-    Ok(())
+    Ok(buf)
+}
+
+/// Number of bytes `send` writes for `msg`, including the length prefix
+pub fn framed_size<T: Serialize + ?Sized>(msg: &T) -> u64 {
+    bincode::serialized_size(msg).unwrap() + mem::size_of::<u16>() as u64
 }
 
 /// Returns `None` on end of stream
@@ -47,8 +57,15 @@ pub async fn send_whole<T: Serialize + ?Sized>(
 /// Receive the entirety of `stream` as a `T`
 pub async fn recv_whole<T: DeserializeOwned>(
     size_limit: usize,
-    mut stream: quinn::RecvStream,
+    stream: quinn::RecvStream,
 ) -> Result<T> {
+    Ok(bincode::deserialize(
+        &recv_whole_bytes(size_limit, stream).await?,
+    )?)
+}
+
+/// Receive the entirety of `stream` without decoding it
+pub async fn recv_whole_bytes(size_limit: usize, mut stream: quinn::RecvStream) -> Result<Vec<u8>> {
     let mut buf = Vec::<u8>::with_capacity(1024);
     let mut cursor = 0;
     loop {
@@ -73,5 +90,24 @@ pub async fn recv_whole<T: DeserializeOwned>(
             }
         }
     }
-    Ok(bincode::deserialize(&buf)?)
+    Ok(buf)
+}
+
+/// Read the protocol version from the start of an encoded hello message
+pub fn protocol_version(hello: &[u8]) -> Result<u16> {
+    Ok(bincode::deserialize(hello)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn framed_size_matches_frame() {
+        let msg = "hello";
+        let buf = frame(&msg).unwrap();
+        assert_eq!(buf.len() as u64, framed_size(&msg));
+        let len = u16::from_le_bytes([buf[0], buf[1]]) as usize;
+        assert_eq!(len + 2, buf.len());
+    }
 }
@@ -0,0 +1,471 @@
+//! Collision of characters with voxel terrain
+//!
+//! Shared by the server and the client so that predicted motion matches authoritative motion.
+
+use crate::{
+    cursor::Cursor,
+    dodeca::{Side, Vertex},
+    graph::Graph,
+    math,
+    proto::Position,
+    world::{voxel_at, ChunkId, Material, VoxelData, SUBDIVISION_FACTOR},
+};
+
+/// Radius of the sphere approximating a character's body
+pub const CHARACTER_RADIUS: f64 = 0.2;
+
+/// Longest distance a character is moved before checking for collisions, a fraction of the size of
+/// a voxel
+const MAX_STEP: f32 = 0.05;
+
+/// Number of bisections used to approach an obstacle, each halving the remaining gap
+const CONTACT_ITERATIONS: u32 = 8;
+
+/// Move a character by `displacement`, given in its local frame, without entering solid voxels
+///
+/// Motion is broken into short steps. Within each, motion along each of the character's local axes
+/// is applied separately, and cut short where it would cause a collision, so that characters slide
+/// along surfaces rather than sticking to them. Characters that already intersect terrain, e.g.
+/// due to a block being placed on them, move freely until they're clear of it.
+///
+/// Returns the displacement actually applied.
+pub fn move_character<N, C>(
+    graph: &Graph<N, C>,
+    voxels: impl Fn(&C) -> &VoxelData,
+    position: &mut Position,
+    displacement: &na::Vector3<f32>,
+) -> na::Vector3<f32> {
+    let mut moved = na::Vector3::zeros();
+    let steps = (displacement.norm() / MAX_STEP).ceil();
+    if steps == 0.0 {
+        return moved;
+    }
+    let step = displacement / steps;
+    for _ in 0..steps as u32 {
+        let stuck = intersects(graph, &voxels, position);
+        for axis in 0..3 {
+            if step[axis] == 0.0 {
+                continue;
+            }
+            let mut offset = na::Vector3::zeros();
+            offset[axis] = step[axis];
+            let candidate = translate(graph, position, &offset);
+            if stuck || !intersects(graph, &voxels, &candidate) {
+                *position = candidate;
+                moved[axis] += step[axis];
+                continue;
+            }
+            // Close the remaining gap, so that resting contact is detectable
+            let (mut clear, mut blocked) = (0.0, 1.0);
+            for _ in 0..CONTACT_ITERATIONS {
+                let mid = (clear + blocked) / 2.0;
+                if intersects(graph, &voxels, &translate(graph, position, &(offset * mid))) {
+                    blocked = mid;
+                } else {
+                    clear = mid;
+                }
+            }
+            if clear > 0.0 {
+                *position = translate(graph, position, &(offset * clear));
+                moved[axis] += step[axis] * clear;
+            }
+        }
+    }
+    moved
+}
+
+/// Whether a character at `position` overlaps any solid voxel
+///
+/// Regions that haven't been generated are considered solid.
+pub fn intersects<N, C>(
+    graph: &Graph<N, C>,
+    voxels: impl Fn(&C) -> &VoxelData,
+    position: &Position,
+) -> bool {
+    let chunks = match nearby_chunks(graph, position) {
+        None => return true,
+        Some(x) => x,
+    };
+    let radius = CHARACTER_RADIUS.tanh();
+    chunks.into_iter().any(|(chunk, to_cube)| {
+        let from_cube = to_cube.try_inverse().unwrap();
+        if !ball_intersects_box(&from_cube, radius, &na::zero(), &na::Vector3::repeat(1.0)) {
+            return false;
+        }
+        let data = match *graph.get_cube(chunk.node, chunk.vertex) {
+            None => return true,
+            Some(ref x) => voxels(x),
+        };
+        if let VoxelData::Empty = *data {
+            return false;
+        }
+        let [lower, upper] = voxel_bounds(&to_cube, radius);
+        (lower[0]..=upper[0]).any(|x| {
+            (lower[1]..=upper[1]).any(|y| {
+                (lower[2]..=upper[2]).any(|z| {
+                    data.get([x, y, z]) != Material::Void
+                        && ball_intersects_voxel(&from_cube, radius, [x, y, z])
+                })
+            })
+        })
+    })
+}
+
+/// Whether a character at `position` overlaps the voxel at `coords` in `chunk`, regardless of its
+/// contents
+pub fn overlaps_voxel<N, C>(
+    graph: &Graph<N, C>,
+    position: &Position,
+    chunk: ChunkId,
+    coords: [usize; 3],
+) -> bool {
+    let radius = CHARACTER_RADIUS.tanh();
+    let chunks = match nearby_chunks(graph, position) {
+        None => return false,
+        Some(x) => x,
+    };
+    chunks.into_iter().any(|(x, to_cube)| {
+        x == chunk && ball_intersects_voxel(&to_cube.try_inverse().unwrap(), radius, coords)
+    })
+}
+
+/// Chunks that a character at `position` might touch, with the transform from its local frame to
+/// each chunk's cube coordinates
+///
+/// In the character's local frame, the Klein model makes its body a Euclidean ball and voxels
+/// convex polyhedra. `None` if the character is beyond the edge of the graph.
+fn nearby_chunks<N, C>(
+    graph: &Graph<N, C>,
+    position: &Position,
+) -> Option<Vec<(ChunkId, na::Matrix4<f64>)>> {
+    let local = position.local.map(|x| x as f64);
+    let center = local * math::origin();
+    let (node, transform) = graph.normalize_point(position.node, &center);
+    if Side::iter().any(|side| side.faces(&(transform * center))) {
+        return None;
+    }
+    let local = transform * local;
+    // The chunks incident to a node extend well over `CHARACTER_RADIUS` beyond it, so they contain
+    // every point the character might touch
+    Some(
+        Vertex::iter()
+            .map(|vertex| {
+                let (node, vertex, transform) = Cursor::from_vertex(node, vertex)
+                    .canonicalize_with_transform(graph)
+                    .unwrap();
+                (
+                    ChunkId::new(node, vertex),
+                    vertex.node_to_cube() * transform * local,
+                )
+            })
+            .collect(),
+    )
+}
+
+/// Whether a ball of Euclidean `radius` at the origin intersects the voxel at `coords`, given the
+/// transform `from_cube` from cube coordinates
+fn ball_intersects_voxel(from_cube: &na::Matrix4<f64>, radius: f64, coords: [usize; 3]) -> bool {
+    let min = na::Vector3::from(coords).map(|i| i as f64) / SUBDIVISION_FACTOR as f64;
+    let max = min.add_scalar(1.0 / SUBDIVISION_FACTOR as f64);
+    ball_intersects_box(from_cube, radius, &min, &max)
+}
+
+/// Inclusive range of voxel indices on each axis that might intersect a ball of `radius` at the
+/// origin, given the transform `to_cube` into cube coordinates
+fn voxel_bounds(to_cube: &na::Matrix4<f64>, radius: f64) -> [[usize; 3]; 2] {
+    let mut lower = na::Vector3::repeat(f64::INFINITY);
+    let mut upper = na::Vector3::repeat(f64::NEG_INFINITY);
+    // The image of the bounding box of the ball bounds the image of the ball
+    for corner in &box_corners(&na::Vector3::repeat(-radius), &na::Vector3::repeat(radius)) {
+        let p = to_cube * corner.push(1.0);
+        if p.w <= 0.0 {
+            return [[0; 3], [SUBDIVISION_FACTOR - 1; 3]];
+        }
+        let p = p.xyz() / p.w;
+        lower = lower.zip_map(&p, f64::min);
+        upper = upper.zip_map(&p, f64::max);
+    }
+    [voxel_at(&lower), voxel_at(&upper)]
+}
+
+/// Whether a ball of Euclidean `radius` at the origin intersects the box between `min` and `max`
+/// in the coordinates mapped from by the projective transform `from_cube`
+fn ball_intersects_box(
+    from_cube: &na::Matrix4<f64>,
+    radius: f64,
+    min: &na::Vector3<f64>,
+    max: &na::Vector3<f64>,
+) -> bool {
+    let mut corners = box_corners(min, max);
+    for corner in &mut corners {
+        let p = from_cube * corner.push(1.0);
+        *corner = p.xyz() / p.w;
+    }
+    let centroid = corners.iter().sum::<na::Vector3<f64>>() / 8.0;
+    let mut inside = true;
+    for face in BOX_FACES.iter() {
+        let (a, b, c, d) = (
+            corners[face[0]],
+            corners[face[1]],
+            corners[face[2]],
+            corners[face[3]],
+        );
+        // Projective maps preserve planarity and convexity but not orientation, so measure
+        // against the interior
+        let normal = (b - a).cross(&(c - a));
+        if normal.dot(&(centroid - a)) * normal.dot(&-a) < 0.0 {
+            inside = false;
+        }
+        if triangle_distance(&a, &b, &c) <= radius || triangle_distance(&a, &c, &d) <= radius {
+            return true;
+        }
+    }
+    inside
+}
+
+/// Corners of the box between `min` and `max`, indexed by bits selecting `max` on x, y, and z
+fn box_corners(min: &na::Vector3<f64>, max: &na::Vector3<f64>) -> [na::Vector3<f64>; 8] {
+    let mut result = [na::zero(); 8];
+    for (i, corner) in result.iter_mut().enumerate() {
+        *corner = na::Vector3::new(
+            if i & 4 != 0 { max.x } else { min.x },
+            if i & 2 != 0 { max.y } else { min.y },
+            if i & 1 != 0 { max.z } else { min.z },
+        );
+    }
+    result
+}
+
+/// Faces of a box, as cycles of indices into `box_corners`
+const BOX_FACES: [[usize; 4]; 6] = [
+    [0, 1, 3, 2],
+    [4, 6, 7, 5],
+    [0, 4, 5, 1],
+    [2, 3, 7, 6],
+    [0, 2, 6, 4],
+    [1, 5, 7, 3],
+];
+
+/// Distance from the origin to the triangle `abc`
+fn triangle_distance(a: &na::Vector3<f64>, b: &na::Vector3<f64>, c: &na::Vector3<f64>) -> f64 {
+    // Locate the closest point by Voronoi region, after Ericson's Real-Time Collision Detection
+    let (ab, ac, ap) = (b - a, c - a, -a);
+    let (d1, d2) = (ab.dot(&ap), ac.dot(&ap));
+    if d1 <= 0.0 && d2 <= 0.0 {
+        return a.norm();
+    }
+    let bp = -b;
+    let (d3, d4) = (ab.dot(&bp), ac.dot(&bp));
+    if d3 >= 0.0 && d4 <= d3 {
+        return b.norm();
+    }
+    let vc = d1 * d4 - d3 * d2;
+    if vc <= 0.0 && d1 >= 0.0 && d3 <= 0.0 {
+        return (a + ab * (d1 / (d1 - d3))).norm();
+    }
+    let cp = -c;
+    let (d5, d6) = (ab.dot(&cp), ac.dot(&cp));
+    if d6 >= 0.0 && d5 <= d6 {
+        return c.norm();
+    }
+    let vb = d5 * d2 - d1 * d6;
+    if vb <= 0.0 && d2 >= 0.0 && d6 <= 0.0 {
+        return (a + ac * (d2 / (d2 - d6))).norm();
+    }
+    let va = d3 * d6 - d5 * d4;
+    if va <= 0.0 && d4 - d3 >= 0.0 && d5 - d6 >= 0.0 {
+        return (b + (c - b) * ((d4 - d3) / ((d4 - d3) + (d5 - d6)))).norm();
+    }
+    let denom = 1.0 / (va + vb + vc);
+    (a + ab * (vb * denom) + ac * (vc * denom)).norm()
+}
+
+/// Move `position` by `offset`, given in its local frame
+fn translate<N, C>(
+    graph: &Graph<N, C>,
+    position: &Position,
+    offset: &na::Vector3<f32>,
+) -> Position {
+    let (direction, distance) = na::Unit::new_and_get(*offset);
+    let local =
+        math::renormalize_isometry(&(position.local * math::translate_along(&direction, distance)));
+    let (node, transition) = graph.normalize_transform(position.node, &local);
+    Position {
+        node,
+        local: transition * local,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::NodeId;
+
+    /// Side of the root node beyond which the test world is solid
+    ///
+    /// Planes containing sides of nodes are aligned with the voxel grid, so the surface of the
+    /// resulting wall is flat.
+    const WALL: Side = Side::A;
+
+    /// Distance from the center of the root node to the wall
+    fn wall_distance() -> f64 {
+        math::distance(&math::origin(), &(WALL.reflection() * math::origin())) / 2.0
+    }
+
+    fn walled_graph() -> Graph<(), VoxelData> {
+        let mut graph = Graph::new();
+        graph.ensure_nearby(NodeId::ROOT, 3);
+        let origin = Position {
+            node: NodeId::ROOT,
+            local: na::Matrix4::identity(),
+        };
+        for (node, vertex, _, transform) in graph.nearby_cubes(origin, 4.0) {
+            let transform = transform.map(|x| x as f64);
+            let solid = |coords: na::Vector3<f64>| WALL.faces(&(transform * coords.push(1.0)));
+            // Chunks are convex, so those whose corners all lie on the open side of the wall do too
+            let open = (0..8).all(|i: u32| {
+                !solid(na::Vector3::new(i & 4, i & 2, i & 1).map(|x| (x != 0) as u32 as f64))
+            });
+            let mut data = VoxelData::Empty;
+            if !open {
+                for x in 0..SUBDIVISION_FACTOR {
+                    for y in 0..SUBDIVISION_FACTOR {
+                        for z in 0..SUBDIVISION_FACTOR {
+                            let center = na::Vector3::new(x, y, z)
+                                .map(|i| (i as f64 + 0.5) / SUBDIVISION_FACTOR as f64);
+                            if solid(center) {
+                                data.set([x, y, z], Material::Stone);
+                            }
+                        }
+                    }
+                }
+            }
+            *graph.get_cube_mut(node, vertex) = Some(data);
+        }
+        graph
+    }
+
+    /// A character at the center of the root node whose x axis points at the wall
+    fn facing_wall() -> Position {
+        let normal = (WALL.reflection() * math::origin()).xyz().map(|x| x as f32);
+        Position {
+            node: NodeId::ROOT,
+            local: na::Rotation3::rotation_between(&na::Vector3::x(), &normal)
+                .unwrap()
+                .to_homogeneous(),
+        }
+    }
+
+    fn origin_distance(graph: &Graph<(), VoxelData>, position: &Position) -> f64 {
+        // Only valid for positions in the root node or its immediate neighbors
+        let mut p = position.local.map(|x| x as f64) * math::origin();
+        if position.node != NodeId::ROOT {
+            let side = Side::iter()
+                .find(|&side| graph.neighbor(NodeId::ROOT, side) == Some(position.node))
+                .unwrap();
+            p = side.reflection() * p;
+        }
+        math::distance(&math::origin(), &p)
+    }
+
+    #[test]
+    fn free_motion() {
+        let graph = walled_graph();
+        let mut position = facing_wall();
+        let displacement = na::Vector3::new(-0.3, 0.2, 0.4);
+        let moved = move_character(&graph, |x| x, &mut position, &displacement);
+        assert!((moved - displacement).norm() < 1e-5);
+
+        let mut position = facing_wall();
+        move_character(
+            &graph,
+            |x| x,
+            &mut position,
+            &na::Vector3::new(0.0, -0.7, 0.0),
+        );
+        assert!((origin_distance(&graph, &position) - 0.7).abs() < 1e-4);
+    }
+
+    #[test]
+    fn blocked() {
+        let graph = walled_graph();
+        let mut position = facing_wall();
+        assert!(!intersects(&graph, |x| x, &position));
+        let moved = move_character(
+            &graph,
+            |x| x,
+            &mut position,
+            &na::Vector3::new(2.0, 0.0, 0.0),
+        );
+        assert!(moved.x < 1.0);
+        assert!(!intersects(&graph, |x| x, &position));
+        let distance = origin_distance(&graph, &position);
+        let limit = wall_distance() - CHARACTER_RADIUS;
+        assert!(distance < limit);
+        assert!(distance > limit - 1e-3);
+    }
+
+    #[test]
+    fn slide() {
+        let graph = walled_graph();
+        let mut position = facing_wall();
+        let moved = move_character(
+            &graph,
+            |x| x,
+            &mut position,
+            &na::Vector3::new(2.0, 0.0, 0.5),
+        );
+        assert!(moved.x < 1.0);
+        assert!((moved.z - 0.5).abs() < 1e-5);
+    }
+
+    #[test]
+    fn escape() {
+        let graph = walled_graph();
+        let mut position = facing_wall();
+        position.local *= math::translate_along(&na::Vector3::x_axis(), wall_distance() as f32);
+        assert!(intersects(&graph, |x| x, &position));
+        let displacement = na::Vector3::new(-0.5, 0.0, 0.0);
+        let moved = move_character(&graph, |x| x, &mut position, &displacement);
+        assert!((moved - displacement).norm() < 1e-5);
+        assert!(!intersects(&graph, |x| x, &position));
+    }
+
+    #[test]
+    fn single_voxel() {
+        let mut graph = Graph::<(), VoxelData>::new();
+        graph.ensure_nearby(NodeId::ROOT, 3);
+        let origin = Position {
+            node: NodeId::ROOT,
+            local: na::Matrix4::identity(),
+        };
+        for (node, vertex, _, _) in graph.nearby_cubes(origin, 4.0) {
+            *graph.get_cube_mut(node, vertex) = Some(VoxelData::Empty);
+        }
+        // An obstacle ahead and slightly to the side, so that the character's center passes it by
+        let target = na::Vector4::new(0.45, 0.07, 0.03, 1.0);
+        let vertex = Vertex::containing(&target);
+        let p = vertex.node_to_cube() * target;
+        let voxel = voxel_at(&(p.xyz() / p.w));
+        let mut data = VoxelData::Empty;
+        data.set(voxel, Material::Stone);
+        *graph.get_cube_mut(NodeId::ROOT, vertex) = Some(data);
+
+        let mut position = origin;
+        let moved = move_character(
+            &graph,
+            |x| x,
+            &mut position,
+            &na::Vector3::new(1.0, 0.0, 0.0),
+        );
+        assert!(moved.x < 0.45);
+        assert!(!intersects(&graph, |x| x, &position));
+        // Resting against the obstacle
+        let mut nudged = position;
+        nudged.local *= math::translate_along(&na::Vector3::x_axis(), 1e-2);
+        assert!(intersects(&graph, |x| x, &nudged));
+        let chunk = ChunkId::new(NodeId::ROOT, vertex);
+        assert!(overlaps_voxel(&graph, &nudged, chunk, voxel));
+        assert!(!overlaps_voxel(&graph, &origin, chunk, voxel));
+    }
+}
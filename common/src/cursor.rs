@@ -48,18 +48,40 @@ impl Cursor {
         })
     }
 
+    /// Sides of the cursor's node incident to the cube, corresponding to the x, y, and z axes respectively
+    #[inline]
+    pub fn sides(self) -> [Side; 3] {
+        [self.a, self.b, self.c]
+    }
+
     /// Node and dodecahedral vertex that contains the representation for this cube in the graph
     pub fn canonicalize<N, C>(self, graph: &Graph<N, C>) -> Option<(NodeId, Vertex)> {
+        self.canonicalize_with_transform(graph)
+            .map(|(node, vertex, _)| (node, vertex))
+    }
+
+    /// Like `canonicalize`, but also returns the transform from the frame of the cursor's node to
+    /// that of the canonical node
+    pub fn canonicalize_with_transform<N, C>(
+        self,
+        graph: &Graph<N, C>,
+    ) -> Option<(NodeId, Vertex, na::Matrix4<f64>)> {
         let mut node = self.node;
+        let mut transform = na::Matrix4::identity();
         for side in [self.a, self.b, self.c].iter().cloned() {
             // missing neighbors are always longer
             if let Some(neighbor) = graph.neighbor(node, side) {
                 if graph.length(neighbor) < graph.length(node) {
                     node = neighbor;
+                    transform = side.reflection() * transform;
                 }
             }
         }
-        Some((node, Vertex::from_sides(self.a, self.b, self.c).unwrap()))
+        Some((
+            node,
+            Vertex::from_sides(self.a, self.b, self.c).unwrap(),
+            transform,
+        ))
     }
 }
 
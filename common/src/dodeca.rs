@@ -58,7 +58,7 @@ impl Side {
 }
 
 /// Vertices of a right dodecahedron
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
 pub enum Vertex {
     A,
     B,
@@ -102,6 +102,38 @@ impl Vertex {
         VERTEX_SIDES[self as usize]
     }
 
+    /// Transform from cube-local coordinates to dodeca-local coordinates
+    ///
+    /// The cube spans [0, 1] on each axis, with its corners at the centers of the dodecahedra
+    /// reached by reflecting across each subset of the canonical sides, in order along x, y, z.
+    #[inline]
+    pub fn cube_to_node(self) -> &'static na::Matrix4<f64> {
+        &CUBE_TO_NODE[self as usize]
+    }
+
+    /// Vertex whose dual cube contains `p`, a point within the dodecahedron
+    ///
+    /// The portion of the dodecahedron within each cube spans [0, 0.5] on every axis of the cube's
+    /// coordinates, so we select the cube in which `p` is nearest to the center of that region.
+    pub fn containing(p: &na::Vector4<f64>) -> Self {
+        let distance = |v: Vertex| {
+            let p = v.node_to_cube() * p;
+            (p.xyz() / p.w)
+                .iter()
+                .map(|x| (x - 0.25).abs())
+                .fold(0.0, f64::max)
+        };
+        Vertex::iter()
+            .min_by(|&x, &y| distance(x).partial_cmp(&distance(y)).unwrap())
+            .unwrap()
+    }
+
+    /// Transform from dodeca-local coordinates to cube-local coordinates
+    #[inline]
+    pub fn node_to_cube(self) -> &'static na::Matrix4<f64> {
+        &NODE_TO_CUBE[self as usize]
+    }
+
     /// Sides across which to reflect the origin to obtain the vertices of the dual cube
     pub fn dual_vertices(
         self,
@@ -169,6 +201,31 @@ lazy_static! {
         result
     };
 
+    /// Transform that converts from cube-local coordinates to dodeca-local coordinates, for each vertex
+    static ref CUBE_TO_NODE: [na::Matrix4<f64>; VERTEX_COUNT] = {
+        let mut result = [na::zero(); VERTEX_COUNT];
+        for v in Vertex::iter() {
+            let origin = math::origin();
+            let [a, b, c] = v.canonical_sides();
+            result[v as usize] = na::Matrix4::from_columns(&[
+                a.reflection().column(3) - origin,
+                b.reflection().column(3) - origin,
+                c.reflection().column(3) - origin,
+                origin,
+            ]);
+        }
+        result
+    };
+
+    /// Inverse of `CUBE_TO_NODE`, for each vertex
+    static ref NODE_TO_CUBE: [na::Matrix4<f64>; VERTEX_COUNT] = {
+        let mut result = [na::zero(); VERTEX_COUNT];
+        for v in Vertex::iter() {
+            result[v as usize] = CUBE_TO_NODE[v as usize].try_inverse().unwrap();
+        }
+        result
+    };
+
     /// Sides incident to a vertex, in canonical order
     static ref VERTEX_SIDES: [[Side; 3]; VERTEX_COUNT] = {
         let mut result = [[Side::A; 3]; VERTEX_COUNT];
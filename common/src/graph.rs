@@ -1,5 +1,6 @@
 #![allow(clippy::len_without_is_empty)]
 
+use std::collections::VecDeque;
 use std::convert::TryFrom;
 use std::fmt;
 use std::num::NonZeroU32;
@@ -9,6 +10,7 @@ use lazy_static::lazy_static;
 use serde::{Deserialize, Serialize};
 
 use crate::{
+    cursor::Cursor,
     dodeca::{Side, Vertex, SIDE_COUNT, VERTEX_COUNT},
     math,
     proto::Position,
@@ -34,6 +36,14 @@ impl<N, C> Graph<N, C> {
         self.nodes.len() as u32
     }
 
+    /// Whether `node` identifies a node in this graph
+    ///
+    /// Useful for validating untrusted input.
+    #[inline]
+    pub fn contains(&self, node: NodeId) -> bool {
+        node.idx() < self.nodes.len()
+    }
+
     /// Nodes created since the last call to `clear_fresh`
     #[inline]
     pub fn fresh(&self) -> &[NodeId] {
@@ -82,7 +92,7 @@ impl<N, C> Graph<N, C> {
             let current_in_range = math::distance(&start_p, &current_p) < distance;
 
             for v in self.cubes_at(current.id) {
-                let v_transform = current.transform * cube_to_node(v);
+                let v_transform = current.transform * v.cube_to_node();
                 if math::distance(&start_p, &(v_transform * math::origin())) < distance {
                     result.push((
                         current.id,
@@ -134,6 +144,11 @@ impl<N, C> Graph<N, C> {
         Vertex::iter().filter(move |&i| exists[i as usize])
     }
 
+    /// Enumerate the cubes incident to `node`, in canonical form
+    pub fn incident_cubes(&self, node: NodeId) -> impl Iterator<Item = (NodeId, Vertex)> + '_ {
+        Vertex::iter().map(move |v| Cursor::from_vertex(node, v).canonicalize(self).unwrap())
+    }
+
     /// Ensure all nodes within `distance` links of `start` exist
     pub fn ensure_nearby(&mut self, start: NodeId, distance: u32) {
         let mut pending = Vec::<(NodeId, u32)>::new();
@@ -154,6 +169,60 @@ impl<N, C> Graph<N, C> {
         }
     }
 
+    /// Enumerate the existing nodes within `distance` links of `start`, including `start`
+    pub fn nearby_nodes(&self, start: NodeId, distance: u32) -> FxHashSet<NodeId> {
+        // Breadth-first, so each node is first reached by a shortest path
+        let mut pending = VecDeque::<(NodeId, u32)>::new();
+        let mut visited = FxHashSet::<NodeId>::default();
+
+        pending.push_back((start, 0));
+        visited.insert(start);
+
+        while let Some((node, current_distance)) = pending.pop_front() {
+            if current_distance == distance {
+                continue;
+            }
+            for side in Side::iter() {
+                let neighbor = match self.neighbor(node, side) {
+                    None => continue,
+                    Some(x) => x,
+                };
+                if visited.insert(neighbor) {
+                    pending.push_back((neighbor, current_distance + 1));
+                }
+            }
+        }
+        visited
+    }
+
+    /// Extend `known` to include `nodes`, returning the nodes added in an order in which they can
+    /// be passed to `insert_child` on a graph containing only `known`
+    ///
+    /// Used to inform peers of part of the graph. Every shorter neighbor of a new node is included,
+    /// and precedes it, as `insert_child` requires.
+    pub fn reveal(
+        &self,
+        known: &mut FxHashSet<NodeId>,
+        nodes: impl IntoIterator<Item = NodeId>,
+    ) -> Vec<NodeId> {
+        let mut pending = nodes.into_iter().collect::<Vec<_>>();
+        let mut result = Vec::new();
+        while let Some(node) = pending.pop() {
+            if !known.insert(node) {
+                continue;
+            }
+            result.push(node);
+            let length = self.length(node);
+            pending.extend(
+                Side::iter()
+                    .filter_map(|side| self.neighbor(node, side))
+                    .filter(|&x| self.length(x) < length && !known.contains(&x)),
+            );
+        }
+        result.sort_unstable_by_key(|&node| self.length(node));
+        result
+    }
+
     #[inline]
     pub fn get(&self, node: NodeId) -> &Option<N> {
         &self.nodes[node.idx()].value
@@ -188,11 +257,23 @@ impl<N, C> Graph<N, C> {
     /// and the transform that moves it there
     pub fn normalize_transform<T: na::RealField>(
         &self,
-        mut reference: NodeId,
+        reference: NodeId,
         original: &na::Matrix4<T>,
+    ) -> (NodeId, na::Matrix4<T>) {
+        self.normalize_point(reference, &(original * math::origin()))
+    }
+
+    /// Given a point `p` relative to a `reference` node, computes the node that it's closest to and
+    /// the transform that moves it there
+    ///
+    /// If the graph doesn't extend far enough, the result may not contain `p`.
+    pub fn normalize_point<T: na::RealField>(
+        &self,
+        mut reference: NodeId,
+        p: &na::Vector4<T>,
     ) -> (NodeId, na::Matrix4<T>) {
         let mut transform = na::Matrix4::identity();
-        let mut location = original * math::origin();
+        let mut location = *p;
         'outer: loop {
             for side in Side::iter() {
                 if !side.faces(&location) {
@@ -217,6 +298,25 @@ impl<N, C> Graph<N, C> {
         self.nodes[node.idx()].parent_side
     }
 
+    /// Sides crossed by the path of parent links from the root to `node`
+    ///
+    /// Unlike `NodeId`s, paths identify nodes independently of the order in which they were created.
+    pub fn path(&self, mut node: NodeId) -> Vec<Side> {
+        let mut result = Vec::with_capacity(self.length(node) as usize);
+        while let Some(side) = self.parent(node) {
+            result.push(side);
+            node = self.neighbor(node, side).unwrap();
+        }
+        result.reverse();
+        result
+    }
+
+    /// Find the node reached by following `path` from the root, creating nodes as needed
+    pub fn ensure_path(&mut self, path: &[Side]) -> NodeId {
+        path.iter()
+            .fold(NodeId::ROOT, |node, &side| self.ensure_neighbor(node, side))
+    }
+
     /// Iterate over every node and its parent
     pub fn tree(&self) -> TreeIter<'_, N, C> {
         TreeIter {
@@ -258,12 +358,19 @@ impl<N, C> Graph<N, C> {
     }
 
     pub fn insert_child(&mut self, parent: NodeId, side: Side) -> NodeId {
-        // Always create shorter nodes first so that self.nodes is always sorted by length, enabling
-        // graceful synchronization of the graph
+        // Always create shorter nodes first so that every node is preceded by its shorter
+        // neighbors, enabling graceful synchronization of the graph
         let shorter_neighbors = self.populate_shorter_neighbors_of_child(parent, side);
+        // Every shorter neighbor is equally valid as a parent, so pick the one across the lowest
+        // side. This makes each node's path from the root independent of the order in which nodes
+        // are created, which world generation relies on.
+        let parent_side = shorter_neighbors
+            .clone()
+            .map(|(side, _)| side)
+            .fold(side, Ord::min);
         let id = NodeId::from_idx(self.nodes.len());
         let length = self.nodes[parent.idx()].length + 1;
-        self.nodes.push(Node::new(Some(side), length));
+        self.nodes.push(Node::new(Some(parent_side), length));
         self.link_neighbors(id, parent, side);
         for (side, neighbor) in shorter_neighbors {
             self.link_neighbors(id, neighbor, side);
@@ -277,7 +384,7 @@ impl<N, C> Graph<N, C> {
         &mut self,
         parent: NodeId,
         parent_side: Side,
-    ) -> impl Iterator<Item = (Side, NodeId)> {
+    ) -> impl Iterator<Item = (Side, NodeId)> + Clone {
         let mut neighbors = [None; 3]; // Maximum number of shorter neighbors is 3
         let mut count = 0;
         for neighbor_side in Side::iter() {
@@ -365,24 +472,13 @@ impl<N, C> Node<N, C> {
     }
 }
 
-fn cube_to_node(v: Vertex) -> na::Matrix4<f64> {
-    let origin = na::Vector4::new(0.0, 0.0, 0.0, 1.0);
-    let [a, b, c] = v.canonical_sides();
-    na::Matrix4::from_columns(&[
-        a.reflection().column(3) - origin,
-        b.reflection().column(3) - origin,
-        c.reflection().column(3) - origin,
-        origin,
-    ])
-}
-
 lazy_static! {
     /// Whether the determinant of the cube-to-node transform is negative
     static ref CUBE_TO_NODE_DETERMINANT_NEGATIVE: [bool; VERTEX_COUNT] = {
         let mut result = [false; VERTEX_COUNT];
 
         for v in Vertex::iter() {
-            result[v as usize] = math::parity(&cube_to_node(v));
+            result[v as usize] = math::parity(v.cube_to_node());
         }
 
         result
@@ -410,6 +506,7 @@ impl<N, C> Iterator for TreeIter<'_, N, C> {
 mod tests {
     use super::*;
     use approx::*;
+    use fxhash::FxHashMap;
 
     #[test]
     fn parent_child_relationships() {
@@ -475,6 +572,79 @@ mod tests {
         }
     }
 
+    #[test]
+    fn nearby_nodes() {
+        let mut graph = Graph::<(), ()>::default();
+        graph.ensure_nearby(NodeId::ROOT, 3);
+        assert_eq!(
+            graph
+                .nearby_nodes(NodeId::ROOT, 0)
+                .into_iter()
+                .collect::<Vec<_>>(),
+            [NodeId::ROOT]
+        );
+        let adjacent = graph.nearby_nodes(NodeId::ROOT, 1);
+        assert_eq!(adjacent.len(), 1 + SIDE_COUNT);
+        assert!(Side::iter()
+            .all(|side| adjacent.contains(&graph.neighbor(NodeId::ROOT, side).unwrap())));
+        let a = graph.neighbor(NodeId::ROOT, Side::A).unwrap();
+        let near_a = graph.nearby_nodes(a, 2);
+        assert!(near_a.is_superset(&adjacent));
+        assert!(near_a.len() > adjacent.len());
+    }
+
+    #[test]
+    fn reveal_subgraph() {
+        let mut a = Graph::<(), ()>::default();
+        a.ensure_nearby(NodeId::ROOT, 4);
+        let far = a
+            .tree()
+            .map(|(side, parent)| a.neighbor(parent, side).unwrap())
+            .max_by_key(|&node| a.length(node))
+            .unwrap();
+        let mut known = std::iter::once(NodeId::ROOT).collect();
+        let revealed = a.reveal(&mut known, a.nearby_nodes(far, 1));
+        assert!(revealed.len() < a.nodes.len() - 1);
+
+        // Reconstruct just the revealed nodes elsewhere
+        let mut b = Graph::<(), ()>::default();
+        let mut ids = std::iter::once((NodeId::ROOT, NodeId::ROOT)).collect::<FxHashMap<_, _>>();
+        for node in revealed {
+            let side = a.parent(node).unwrap();
+            let parent = ids[&a.neighbor(node, side).unwrap()];
+            ids.insert(node, b.insert_child(parent, side));
+        }
+        assert_eq!(b.nodes.len(), ids.len(), "no nodes were created implicitly");
+        for (&node_a, &node_b) in &ids {
+            assert_eq!(a.length(node_a), b.length(node_b));
+            for side in Side::iter() {
+                if let Some(neighbor) = b.neighbor(node_b, side) {
+                    let neighbor_a = ids.iter().find(|&(_, &x)| x == neighbor).unwrap().0;
+                    assert_eq!(a.neighbor(node_a, side), Some(*neighbor_a));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn path_roundtrip() {
+        let mut a = Graph::<(), ()>::default();
+        a.ensure_nearby(NodeId::ROOT, 3);
+        let far = a
+            .tree()
+            .map(|(side, parent)| a.neighbor(parent, side).unwrap())
+            .max_by_key(|&node| a.length(node))
+            .unwrap();
+        let path = a.path(far);
+        assert_eq!(path.len() as u32, a.length(far));
+
+        let mut b = Graph::<(), ()>::default();
+        let node = b.ensure_path(&path);
+        assert_eq!(b.path(node), path);
+        assert_eq!(b.length(node), a.length(far));
+        assert_eq!(b.ensure_path(&[]), NodeId::ROOT);
+    }
+
     #[test]
     fn rebuild_from_tree() {
         let mut a = Graph::<(), ()>::default();
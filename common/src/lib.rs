@@ -7,12 +7,18 @@ use rand::{
 mod id;
 
 pub mod codec;
+pub mod collision;
 pub mod cursor;
 pub mod dodeca;
 pub mod graph;
 pub mod math;
+pub mod movement;
 pub mod proto;
+pub mod raycast;
+pub mod recording;
+pub mod session;
 pub mod world;
+pub mod worldgen;
 
 // Stable IDs made of 8 random bytes for easy persistent references
 mkid!(EntityId: u64);
@@ -129,8 +129,40 @@ pub fn parity<N: RealField>(m: &na::Matrix4<N>) -> bool {
     m.fixed_slice::<na::U3, na::U3>(0, 0).determinant() < na::zero::<N>()
 }
 
+/// Direct isometry a fraction `t` of the way from `a` to `b`
+///
+/// The translation between them is interpolated along a geodesic and the rotation spherically, so
+/// intermediate isometries move steadily along the shortest path.
+pub fn interpolate_isometry<N: RealField>(
+    a: &na::Matrix4<N>,
+    b: &na::Matrix4<N>,
+    t: N,
+) -> na::Matrix4<N> {
+    let relative = Isometry::from_homogeneous(&(mtranspose(a) * b));
+    let translation = match na::Unit::try_new(relative.translation.xyz(), N::default_epsilon()) {
+        None => na::Matrix4::identity(),
+        Some(direction) => {
+            let distance = relative.translation.w.max(N::one()).acosh();
+            translate_along(&direction, distance * t)
+        }
+    };
+    // `q` and `-q` represent the same rotation; take the shorter way around
+    let rotation = if relative.rotation.w < N::zero() {
+        na::UnitQuaternion::new_unchecked(-relative.rotation.into_inner())
+    } else {
+        relative.rotation
+    };
+    let rotation = na::UnitQuaternion::identity().slerp(&rotation, t);
+    a * translation * rotation.to_homogeneous()
+}
+
+/// Minkowski transpose, the inverse of an isometry
+pub fn mtranspose<N: RealField>(m: &na::Matrix4<N>) -> na::Matrix4<N> {
+    i31::<N>() * m.transpose() * i31::<N>()
+}
+
 /// Minkowski inner product, aka <a, b>_h
-fn mip<N: RealField>(a: &na::Vector4<N>, b: &na::Vector4<N>) -> N {
+pub fn mip<N: RealField>(a: &na::Vector4<N>, b: &na::Vector4<N>) -> N {
     a.x * b.x + a.y * b.y + a.z * b.z - a.w * b.w
 }
 
@@ -179,6 +211,33 @@ mod tests {
         );
     }
 
+    #[test]
+    fn mtranspose_inverts() {
+        let m = translate_along(
+            &na::Unit::new_normalize(na::Vector3::new(1.0, -2.0, 0.5)),
+            0.7,
+        ) * na::UnitQuaternion::from_euler_angles(0.3, -0.2, 1.1).to_homogeneous();
+        assert_abs_diff_eq!(mtranspose(&m) * m, na::Matrix4::identity(), epsilon = 1e-5);
+    }
+
+    #[test]
+    fn interpolate_isometry_endpoints() {
+        let a = translate_along(&na::Vector3::x_axis(), 0.4)
+            * na::UnitQuaternion::from_euler_angles(0.1, 0.2, 0.3).to_homogeneous();
+        let b = translate_along(
+            &na::Unit::new_normalize(na::Vector3::new(0.0, 1.0, -1.0)),
+            1.3,
+        ) * na::UnitQuaternion::from_euler_angles(-0.5, 0.0, 2.0).to_homogeneous();
+        assert_abs_diff_eq!(interpolate_isometry(&a, &b, 0.0), a, epsilon = 1e-5);
+        assert_abs_diff_eq!(interpolate_isometry(&a, &b, 1.0), b, epsilon = 1e-5);
+
+        // The midpoint is halfway along the geodesic between the two positions
+        let (p, q) = (a * origin(), b * origin());
+        let mid = interpolate_isometry(&a, &b, 0.5) * origin();
+        assert_abs_diff_eq!(distance(&p, &mid), distance(&p, &q) / 2.0, epsilon = 1e-5);
+        assert_abs_diff_eq!(distance(&mid, &q), distance(&p, &q) / 2.0, epsilon = 1e-5);
+    }
+
     #[test]
     fn translate_identity() {
         let a = na::Vector4::new(-0.5, -0.5, 0.0, 1.0);
@@ -0,0 +1,355 @@
+//! Character motion under player control
+//!
+//! Shared by the server and the client so that predicted motion matches authoritative motion.
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    collision,
+    graph::Graph,
+    math,
+    proto::{Command, MovementMode, Position},
+    world::VoxelData,
+    worldgen::NodeState,
+};
+
+/// Top speed of a flying character
+pub const FLY_SPEED: f32 = 1.0;
+/// Top speed of a walking character
+pub const WALK_SPEED: f32 = 1.0;
+/// Top speed of a crouching character
+pub const CROUCH_SPEED: f32 = 0.4;
+/// Acceleration towards the ground of a walking character
+pub const GRAVITY: f32 = 4.0;
+/// Speed away from the ground at the start of a jump
+pub const JUMP_SPEED: f32 = 1.6;
+/// Fastest speed a character can fall at
+pub const MAX_FALL_SPEED: f32 = 8.0;
+/// Tallest obstacle a walking character climbs without jumping
+pub const STEP_HEIGHT: f32 = 0.2;
+
+/// Motion that a character's owner has requested
+#[derive(Debug, Copy, Clone)]
+pub struct Input {
+    /// Velocity in the character's local frame, as a fraction of its top speed
+    pub velocity: na::Vector3<f32>,
+    pub mode: MovementMode,
+    pub jump: bool,
+    pub crouch: bool,
+}
+
+impl Default for Input {
+    fn default() -> Self {
+        Self {
+            velocity: na::zero(),
+            mode: MovementMode::Fly,
+            jump: false,
+            crouch: false,
+        }
+    }
+}
+
+impl From<&Command> for Input {
+    fn from(command: &Command) -> Self {
+        Self {
+            velocity: command.velocity,
+            mode: command.mode,
+            jump: command.jump,
+            crouch: command.crouch,
+        }
+    }
+}
+
+/// Motion of a character carried over between steps
+#[derive(Debug, Copy, Clone, Default, Serialize, Deserialize)]
+pub struct State {
+    /// Speed towards the ground, negative while rising
+    pub fall_speed: f32,
+    /// Whether the character is standing on something
+    pub on_ground: bool,
+}
+
+/// Advance a character's motion by `dt` seconds
+pub fn step<C>(
+    graph: &Graph<NodeState, C>,
+    voxels: impl Fn(&C) -> &VoxelData,
+    position: &mut Position,
+    state: &mut State,
+    input: &Input,
+    dt: f32,
+) {
+    let velocity = if input.velocity.norm() > 1.0 {
+        input.velocity.normalize()
+    } else {
+        input.velocity
+    };
+    let up = match (input.mode, local_up(graph, position)) {
+        (MovementMode::Walk, Some(up)) => up,
+        _ => {
+            // Flying, or somewhere the ground plane isn't known
+            *state = State::default();
+            collision::move_character(graph, voxels, position, &(velocity * FLY_SPEED * dt));
+            return;
+        }
+    };
+
+    // Resolve motion in a frame whose y axis points away from the ground, so that collisions with
+    // level ground block vertical motion without interfering with horizontal motion
+    let align = na::UnitQuaternion::rotation_between(&na::Vector3::y(), &up).unwrap_or_else(|| {
+        na::UnitQuaternion::from_axis_angle(&na::Vector3::x_axis(), std::f32::consts::PI)
+    });
+    let start = Position {
+        node: position.node,
+        local: position.local * align.to_homogeneous(),
+    };
+    let mut aligned = start;
+
+    // Looking up or down shouldn't slow a walking character
+    let velocity = align.inverse() * velocity;
+    let horizontal = na::Vector3::new(velocity.x, 0.0, velocity.z);
+    let horizontal = match horizontal.try_normalize(1e-6) {
+        Some(direction) => direction * velocity.norm(),
+        None => na::zero(),
+    };
+    let speed = if input.crouch {
+        CROUCH_SPEED
+    } else {
+        WALK_SPEED
+    };
+    walk(
+        graph,
+        &voxels,
+        &mut aligned,
+        &(horizontal * speed * dt),
+        state.on_ground,
+    );
+
+    if state.on_ground && input.jump {
+        state.fall_speed = -JUMP_SPEED;
+    }
+    state.fall_speed = (state.fall_speed + GRAVITY * dt).min(MAX_FALL_SPEED);
+    fall(graph, &voxels, &mut aligned, state, dt);
+
+    if aligned.node == start.node && aligned.local == start.local {
+        // Leave resting characters exactly as they were, so they aren't mistaken for moving ones
+        return;
+    }
+    *position = Position {
+        node: aligned.node,
+        local: math::renormalize_isometry(&(aligned.local * align.inverse().to_homogeneous())),
+    };
+}
+
+/// Direction away from the ground in `position`'s local frame
+fn local_up<C>(
+    graph: &Graph<NodeState, C>,
+    position: &Position,
+) -> Option<na::Unit<na::Vector3<f32>>> {
+    let up = graph.get(position.node).as_ref()?.up();
+    let up = math::mtranspose(&position.local.map(|x| x as f64)) * up;
+    // At the origin, the tangent component of the plane's normal is its spatial part
+    na::Unit::try_new(up.xyz().map(|x| x as f32), 1e-6)
+}
+
+/// Move horizontally by `displacement`, climbing small obstacles if `on_ground`
+fn walk<C>(
+    graph: &Graph<NodeState, C>,
+    voxels: impl Fn(&C) -> &VoxelData,
+    position: &mut Position,
+    displacement: &na::Vector3<f32>,
+    on_ground: bool,
+) {
+    let moved = collision::move_character(graph, &voxels, position, displacement);
+    let remaining = displacement - moved;
+    if !on_ground || remaining.norm() < CONTACT_EPSILON {
+        return;
+    }
+    let mut raised = *position;
+    let lift = collision::move_character(
+        graph,
+        &voxels,
+        &mut raised,
+        &(na::Vector3::y() * STEP_HEIGHT),
+    );
+    let progress = collision::move_character(graph, &voxels, &mut raised, &remaining);
+    if progress.norm() < CONTACT_EPSILON {
+        return;
+    }
+    collision::move_character(graph, &voxels, &mut raised, &-lift);
+    *position = raised;
+}
+
+/// Move vertically according to `state.fall_speed`, updating `state` to reflect any contact
+fn fall<C>(
+    graph: &Graph<NodeState, C>,
+    voxels: impl Fn(&C) -> &VoxelData,
+    position: &mut Position,
+    state: &mut State,
+    dt: f32,
+) {
+    let distance = state.fall_speed * dt;
+    if distance < 0.0 {
+        let moved =
+            collision::move_character(graph, &voxels, position, &(na::Vector3::y() * -distance));
+        if moved.y < -distance - CONTACT_EPSILON {
+            // Bumped into a ceiling
+            state.fall_speed = 0.0;
+        }
+        state.on_ground = false;
+        return;
+    }
+
+    // Stay on the ground while walking down slopes and steps
+    let reach = if state.on_ground {
+        distance.max(STEP_HEIGHT)
+    } else {
+        distance
+    };
+    let mut lowered = *position;
+    let moved =
+        -collision::move_character(graph, &voxels, &mut lowered, &(na::Vector3::y() * -reach)).y;
+    if moved < reach - CONTACT_EPSILON {
+        *position = lowered;
+        state.fall_speed = 0.0;
+        state.on_ground = true;
+    } else if reach > distance {
+        // Walked off an edge
+        collision::move_character(graph, &voxels, position, &(na::Vector3::y() * -distance));
+        state.on_ground = false;
+    } else {
+        *position = lowered;
+        state.on_ground = false;
+    }
+}
+
+/// Shortfall in motion beyond which a character is considered to have made contact
+const CONTACT_EPSILON: f32 = 1e-5;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{dodeca::Side, graph::NodeId, world::ChunkId, worldgen::ChunkParams};
+
+    const SEED: u64 = 0x1234_5678;
+    const DT: f32 = 0.1;
+
+    fn world() -> Graph<NodeState, VoxelData> {
+        let mut graph = Graph::new();
+        *graph.get_mut(NodeId::ROOT) = Some(NodeState::root(SEED));
+        graph.ensure_nearby(NodeId::ROOT, 3);
+        for i in 0..graph.fresh().len() {
+            let node = graph.fresh()[i];
+            *graph.get_mut(node) = Some(NodeState::from_parent(&graph, node));
+        }
+        graph.clear_fresh();
+        for (node, vertex, _, _) in graph.nearby_cubes(origin(), 3.0) {
+            if let Some(params) = ChunkParams::new(&graph, ChunkId::new(node, vertex)) {
+                *graph.get_cube_mut(node, vertex) = Some(params.generate());
+            }
+        }
+        graph
+    }
+
+    fn origin() -> Position {
+        Position {
+            node: NodeId::ROOT,
+            local: na::Matrix4::identity(),
+        }
+    }
+
+    /// Distance of `position` above the ground plane
+    fn height(graph: &Graph<NodeState, VoxelData>, position: &Position) -> f64 {
+        let up = graph.get(position.node).as_ref().unwrap().up();
+        let p = position.local.map(|x| x as f64) * math::origin();
+        math::mip(up, &p).asinh()
+    }
+
+    fn walk_input() -> Input {
+        Input {
+            mode: MovementMode::Walk,
+            ..Input::default()
+        }
+    }
+
+    /// Drop a character at the root until it lands
+    fn land(graph: &Graph<NodeState, VoxelData>) -> (Position, State) {
+        let mut position = origin();
+        let mut state = State::default();
+        for _ in 0..50 {
+            step(graph, |x| x, &mut position, &mut state, &walk_input(), DT);
+            if state.on_ground {
+                return (position, state);
+            }
+        }
+        panic!("never landed");
+    }
+
+    #[test]
+    fn flying_ignores_gravity() {
+        let graph = world();
+        let mut position = origin();
+        let mut state = State::default();
+        for _ in 0..10 {
+            step(
+                &graph,
+                |x| x,
+                &mut position,
+                &mut state,
+                &Input::default(),
+                DT,
+            );
+        }
+        assert_eq!(position.node, NodeId::ROOT);
+        assert_eq!(position.local, na::Matrix4::identity());
+    }
+
+    #[test]
+    fn walking() {
+        let graph = world();
+        let start_height = height(&graph, &origin());
+        let (mut position, mut state) = land(&graph);
+        assert!(height(&graph, &position) < start_height);
+
+        // Resting characters stay put, once they've settled into contact with the ground
+        step(&graph, |x| x, &mut position, &mut state, &walk_input(), DT);
+        let rest = position;
+        for _ in 0..5 {
+            step(&graph, |x| x, &mut position, &mut state, &walk_input(), DT);
+            assert!(state.on_ground);
+        }
+        assert_eq!(position.node, rest.node);
+        assert_eq!(position.local, rest.local);
+
+        // Jumping leaves the ground, then returns to it
+        let jump = Input {
+            jump: true,
+            ..walk_input()
+        };
+        step(&graph, |x| x, &mut position, &mut state, &jump, DT);
+        assert!(!state.on_ground);
+        assert!(height(&graph, &position) > height(&graph, &rest));
+        let mut airtime = 0;
+        while !state.on_ground {
+            step(&graph, |x| x, &mut position, &mut state, &walk_input(), DT);
+            airtime += 1;
+            assert!(airtime < 20, "never landed");
+        }
+
+        // Looking down doesn't keep a character from walking
+        let forward = Input {
+            velocity: na::Vector3::new(0.0, -0.9, -0.1),
+            ..walk_input()
+        };
+        let before = position;
+        step(&graph, |x| x, &mut position, &mut state, &forward, DT);
+        let mut after = position.local * math::origin();
+        if position.node != before.node {
+            let side = Side::iter()
+                .find(|&side| graph.neighbor(before.node, side) == Some(position.node))
+                .unwrap();
+            after = side.reflection().map(|x| x as f32) * after;
+        }
+        let distance = math::distance(&(before.local * math::origin()), &after);
+        assert!(distance > 0.5 * WALK_SPEED * DT, "{}", distance);
+    }
+}
@@ -1,15 +1,139 @@
+use std::fmt;
+
 use serde::{Deserialize, Serialize};
 
-use crate::{dodeca, graph::NodeId, EntityId, Step};
+use crate::{
+    dodeca,
+    graph::NodeId,
+    math, movement,
+    world::{ChunkId, Material},
+    EntityId, Step,
+};
+
+/// Version of the protocol defined in this module
+///
+/// Must be incremented on any incompatible change to the messages herein. Hello messages begin
+/// with the version, so that it can be read even from peers whose messages are otherwise
+/// unintelligible.
+pub const PROTOCOL_VERSION: u16 = 2;
+
+/// Optional protocol features, negotiated during the handshake
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, Default, Eq, PartialEq)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    /// Features supported by this build
+    pub const SUPPORTED: Self = Self(0);
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn intersection(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ClientHello {
+    /// Must remain first; see `PROTOCOL_VERSION`
+    pub protocol_version: u16,
+    /// Features the client supports
+    pub capabilities: Capabilities,
     pub name: String,
+    /// Secret proving the client's right to the character called `name`, if one exists
+    ///
+    /// Should be random and kept by the client between connections.
+    pub credential: [u8; 32],
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ServerHello {
+    /// Must remain first; see `PROTOCOL_VERSION`
+    pub protocol_version: u16,
+    /// Features supported by both the client and the server, which may be used freely
+    pub capabilities: Capabilities,
     pub character: EntityId,
+    /// Seed from which all world contents are generated
+    pub world_seed: u64,
+    /// Number of steps simulated per second
+    pub rate: u16,
+}
+
+/// Why the server closed a connection, conveyed as the application error code of the close
+///
+/// The close's reason bytes may carry human-readable details.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum DisconnectReason {
+    /// The connection was closed without error
+    Closed,
+    /// The server refused the client's hello, e.g. for using the name of another's character
+    Rejected,
+    /// The client and server don't speak compatible protocol versions
+    VersionMismatch,
+    /// The server isn't accepting more clients
+    ServerFull,
+    /// The client wasn't reading the server's messages quickly enough
+    TooSlow,
+    /// The client was removed at the server's discretion
+    Kicked,
+    /// The server is shutting down
+    Shutdown,
+    /// A message was malformed or couldn't be framed
+    ProtocolError,
+    /// The requested character is controlled by another connection, which may yet be dropped
+    AlreadyConnected,
+}
+
+impl DisconnectReason {
+    pub fn code(self) -> u32 {
+        use DisconnectReason::*;
+        match self {
+            Closed => 0,
+            Rejected => 1,
+            VersionMismatch => 2,
+            ServerFull => 3,
+            TooSlow => 4,
+            Kicked => 5,
+            Shutdown => 6,
+            ProtocolError => 7,
+            AlreadyConnected => 8,
+        }
+    }
+
+    /// Returns `None` for codes not known to this build
+    pub fn from_code(code: u64) -> Option<Self> {
+        use DisconnectReason::*;
+        Some(match code {
+            0 => Closed,
+            1 => Rejected,
+            2 => VersionMismatch,
+            3 => ServerFull,
+            4 => TooSlow,
+            5 => Kicked,
+            6 => Shutdown,
+            7 => ProtocolError,
+            8 => AlreadyConnected,
+            _ => return None,
+        })
+    }
+}
+
+impl fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use DisconnectReason::*;
+        f.pad(match *self {
+            Closed => "connection closed",
+            Rejected => "rejected by server",
+            VersionMismatch => "incompatible server version",
+            ServerFull => "server full",
+            TooSlow => "too slow to keep up with server",
+            Kicked => "kicked by server",
+            Shutdown => "server shut down",
+            ProtocolError => "protocol error",
+            AlreadyConnected => "already connected",
+        })
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Copy, Clone)]
@@ -18,11 +142,100 @@ pub struct Position {
     pub local: na::Matrix4<f32>,
 }
 
+/// Compact encoding of a `Position`, for frequent transmission
+///
+/// Equal positions always encode identically, so encodings can be compared to detect change.
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, PartialEq)]
+pub struct PackedPosition {
+    pub node: NodeId,
+    /// Minkowski coordinates of the image of the origin, with an implicit w
+    translation: na::Vector3<f32>,
+    rotation: PackedRotation,
+    /// Whether the isometry reverses winding, as it does after passing through an odd number of
+    /// node sides
+    mirrored: bool,
+}
+
+impl PackedPosition {
+    pub fn new(position: &Position) -> Self {
+        let mirrored = math::parity(&position.local);
+        let local = if mirrored {
+            position.local * mirror()
+        } else {
+            position.local
+        };
+        let isometry = math::Isometry::from_homogeneous(&local);
+        Self {
+            node: position.node,
+            translation: isometry.translation.xyz(),
+            rotation: PackedRotation::new(&isometry.rotation),
+            mirrored,
+        }
+    }
+
+    pub fn unpack(&self) -> Position {
+        let translation = self.translation;
+        let translation = math::HPoint::new(translation.x, translation.y, translation.z);
+        let local =
+            math::Isometry::from_parts(translation.to_homogeneous(), self.rotation.unpack())
+                .to_homogeneous();
+        Position {
+            node: self.node,
+            local: if self.mirrored {
+                local * mirror()
+            } else {
+                local
+            },
+        }
+    }
+}
+
+/// Reflection through the local yz plane, which makes any indirect isometry direct
+fn mirror() -> na::Matrix4<f32> {
+    na::Matrix4::from_diagonal(&na::Vector4::new(-1.0, 1.0, 1.0, 1.0))
+}
+
+/// A unit quaternion with each component quantized to 16 bits
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, Eq, PartialEq)]
+pub struct PackedRotation([i16; 4]);
+
+impl PackedRotation {
+    pub fn new(rotation: &na::UnitQuaternion<f32>) -> Self {
+        // `q` and `-q` represent the same rotation; pick one so that encodings are unique
+        let q = if rotation.w < 0.0 {
+            -rotation.coords
+        } else {
+            rotation.coords
+        };
+        let quantize = |x: f32| (x * i16::MAX as f32).round() as i16;
+        Self([quantize(q.x), quantize(q.y), quantize(q.z), quantize(q.w)])
+    }
+
+    pub fn unpack(&self) -> na::UnitQuaternion<f32> {
+        let [x, y, z, w] = self.0;
+        let dequantize = |x: i16| x as f32 / i16::MAX as f32;
+        na::UnitQuaternion::from_quaternion(na::Quaternion::new(
+            dequantize(w),
+            dequantize(x),
+            dequantize(y),
+            dequantize(z),
+        ))
+    }
+}
+
+/// Changes to frequently updated state since the recipient was last informed of it
+///
+/// Entities whose state the recipient already has are omitted.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct StateDelta {
     pub step: Step,
-    pub positions: Vec<(EntityId, Position)>,
-    pub character_orientations: Vec<(EntityId, na::UnitQuaternion<f32>)>,
+    /// The most recent `Command::step` from the recipient to have been applied
+    pub latest_command: Step,
+    /// Motion of the recipient's character after `latest_command` was applied
+    pub motion: movement::State,
+    /// Always includes the recipient's own character, for reconciliation with its prediction
+    pub positions: Vec<(EntityId, PackedPosition)>,
+    pub character_orientations: Vec<(EntityId, PackedRotation)>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -31,16 +244,69 @@ pub struct Spawns {
     pub spawns: Vec<(EntityId, Vec<Component>)>,
     pub despawns: Vec<EntityId>,
     pub nodes: Vec<FreshNode>,
+    /// Accepted modifications to the world, to be applied after `nodes` are inserted
+    pub block_updates: Vec<BlockUpdate>,
+    /// Chat messages sent since the last step
+    pub chat: Vec<ChatMessage>,
+}
+
+/// Messages sent by clients after `ClientHello`, one per stream
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ClientMessage {
+    Command(Command),
+    /// Request to modify the world
+    BlockUpdate(BlockUpdate),
+    /// Text to be shown to every player, at most `MAX_CHAT_LEN` bytes long
+    Chat(String),
+}
+
+/// Longest chat message the server will accept, in bytes
+pub const MAX_CHAT_LEN: usize = 256;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ChatMessage {
+    pub sender: EntityId,
+    /// Name of the sender's character
+    pub name: String,
+    pub text: String,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Command {
+    /// Sequence number of the command, increasing by one with every step the client simulates
+    ///
+    /// The server applies each command for a single step, in order.
     pub step: Step,
     /// The node that `orientation` and `velocity` are relative to
     pub node: NodeId,
     pub orientation: na::UnitQuaternion<f32>,
     /// Relative to the character's current position
     pub velocity: na::Vector3<f32>,
+    pub mode: MovementMode,
+    /// Whether to jump, if walking on the ground
+    pub jump: bool,
+    /// Whether to move slowly, if walking
+    pub crouch: bool,
+}
+
+/// How a character moves
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, Eq, PartialEq)]
+pub enum MovementMode {
+    /// Free motion in every direction
+    Fly,
+    /// Motion along the ground, under gravity
+    Walk,
+}
+
+/// Replacement of a single voxel's material
+///
+/// Breaking a block is represented by replacing it with `Material::Void`.
+#[derive(Debug, Serialize, Deserialize, Copy, Clone)]
+pub struct BlockUpdate {
+    pub chunk_id: ChunkId,
+    /// Coordinates of the voxel within the chunk, each less than `SUBDIVISION_FACTOR`
+    pub coords: [u8; 3],
+    pub new_material: Material,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -49,8 +315,13 @@ pub enum Component {
     Position(Position),
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// A node newly made known to a client
+///
+/// Clients only learn of part of the graph, so they number nodes independently. Node IDs in messages
+/// are always the server's.
+#[derive(Debug, Serialize, Deserialize, Copy, Clone)]
 pub struct FreshNode {
+    pub id: NodeId,
     /// The side joining the new node to `parent`
     pub side: dodeca::Side,
     pub parent: NodeId,
@@ -61,3 +332,54 @@ pub struct Character {
     pub name: String,
     pub orientation: na::UnitQuaternion<f32>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::*;
+
+    #[test]
+    fn pack_position() {
+        let rotation =
+            na::UnitQuaternion::from_axis_angle(&na::Vector3::y_axis(), 2.0).to_homogeneous();
+        let translation = math::translate_along(&na::Vector3::x_axis(), 0.7);
+        for &mirrored in &[false, true] {
+            let mut local = translation * rotation;
+            if mirrored {
+                local = dodeca::Side::A.reflection().map(|x| x as f32) * local;
+            }
+            let position = Position {
+                node: NodeId::ROOT,
+                local,
+            };
+            let unpacked = PackedPosition::new(&position).unpack();
+            assert_abs_diff_eq!(unpacked.local, local, epsilon = 1e-4);
+        }
+    }
+
+    #[test]
+    fn hello_version_prefix() {
+        // Peers rely on being able to read the version alone from any hello
+        let hello = ClientHello {
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: Capabilities::SUPPORTED,
+            name: "alice".into(),
+            credential: [0xAB; 32],
+        };
+        let data = bincode::serialize(&hello).unwrap();
+        assert_eq!(
+            bincode::deserialize::<u16>(&data).unwrap(),
+            PROTOCOL_VERSION
+        );
+    }
+
+    #[test]
+    fn disconnect_codes() {
+        let mut code = 0;
+        while let Some(reason) = DisconnectReason::from_code(code) {
+            assert_eq!(u64::from(reason.code()), code);
+            code += 1;
+        }
+        assert_eq!(code, 9);
+    }
+}
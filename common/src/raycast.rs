@@ -0,0 +1,314 @@
+//! Tracing rays through the voxels of the world
+
+use crate::{
+    cursor::{Cursor, Dir},
+    dodeca::Vertex,
+    graph::Graph,
+    math,
+    proto::Position,
+    world::{voxel_at, ChunkId, Material, VoxelData, SUBDIVISION_FACTOR},
+};
+
+/// A voxel struck by a ray
+#[derive(Debug, Copy, Clone)]
+pub struct RayHit {
+    pub chunk: ChunkId,
+    /// Coordinates of the struck voxel within `chunk`
+    pub voxel: [usize; 3],
+    /// Outward normal of the face through which the ray entered the voxel, in the sense of
+    /// `Cursor::step` from `Cursor::from_vertex(chunk.node, chunk.vertex)`
+    ///
+    /// `None` if the ray began inside the voxel.
+    pub face: Option<Dir>,
+    /// Distance from the start of the ray to the point of impact
+    pub distance: f64,
+}
+
+/// Find the first solid voxel within `max_distance` along the geodesic leaving `start` towards
+/// `direction`, given in `start`'s local frame
+///
+/// `voxels` extracts the voxel data from a chunk. Chunks that haven't been generated stop the ray
+/// as if nothing was struck.
+pub fn raycast<N, C>(
+    graph: &Graph<N, C>,
+    voxels: impl Fn(&C) -> &VoxelData,
+    start: &Position,
+    direction: &na::Unit<na::Vector3<f32>>,
+    max_distance: f64,
+) -> Option<RayHit> {
+    let local = start.local.map(|x| x as f64);
+    // The ray is represented by a point and a unit tangent vector at that point, such that the point
+    // `ray.0 + u * ray.1` lies `atanh(u)` along the ray. Because both vectors are always transformed
+    // together, this parameterization holds in every frame, including the projective coordinates
+    // of a chunk.
+    let mut ray = (
+        local * math::origin(),
+        local * direction.into_inner().map(|x| x as f64).push(0.0),
+    );
+    let max_u = max_distance.tanh();
+
+    // Find the chunk containing the start of the ray, judged by a point just ahead of it so that
+    // rays starting on a boundary begin in the chunk they're headed into
+    let probe = ray.0 + ray.1 * 1e-9;
+    let (node, vertex, transform) = Cursor::from_vertex(start.node, Vertex::containing(&probe))
+        .canonicalize_with_transform(graph)?;
+    ray = (transform * ray.0, transform * ray.1);
+    let mut chunk = ChunkId::new(node, vertex);
+    let mut u = 0.0;
+    let mut stalled = 0;
+    let mut entered = false;
+
+    loop {
+        let data = voxels(graph.get_cube(chunk.node, chunk.vertex).as_ref()?);
+        let to_cube = chunk.vertex.node_to_cube();
+        let (a, b) = (to_cube * ray.0, to_cube * ray.1);
+
+        // Locate the point of entry within the chunk
+        let entry = (a + b * u).xyz() / (a.w + b.w * u);
+        let mut voxel = voxel_at(&entry);
+        let mut face = if entered {
+            // The face lies on whichever boundary of the chunk the entry point is closest to
+            let (axis, near_zero) = (0..3)
+                .map(|axis| (axis, entry[axis] < 0.5))
+                .min_by(|&(x, x_zero), &(y, y_zero)| {
+                    let dist = |axis: usize, zero| {
+                        if zero {
+                            entry[axis].abs()
+                        } else {
+                            (1.0 - entry[axis]).abs()
+                        }
+                    };
+                    dist(x, x_zero).partial_cmp(&dist(y, y_zero)).unwrap()
+                })
+                .unwrap();
+            Some(axis_dir(axis, !near_zero))
+        } else {
+            None
+        };
+
+        // Walk through the chunk's voxels
+        let (axis, positive) = loop {
+            if data.get(voxel) != Material::Void {
+                return Some(RayHit {
+                    chunk,
+                    voxel,
+                    face,
+                    distance: u.atanh(),
+                });
+            }
+
+            // Find the first voxel boundary the ray crosses. Motion along each axis in projective
+            // coordinates is monotonic, so the direction of travel is fixed.
+            let mut next = None::<(usize, bool, f64)>;
+            for axis in 0..3 {
+                let motion = b[axis] * a.w - a[axis] * b.w;
+                if motion == 0.0 {
+                    continue;
+                }
+                let positive = motion > 0.0;
+                let boundary = (voxel[axis] + positive as usize) as f64 / SUBDIVISION_FACTOR as f64;
+                let crossing = ((boundary * a.w - a[axis]) / (b[axis] - boundary * b.w)).max(u);
+                match next {
+                    Some((_, _, best)) if best <= crossing => {}
+                    _ => next = Some((axis, positive, crossing)),
+                }
+            }
+            let (axis, positive, crossing) = next?;
+            if crossing > max_u {
+                return None;
+            }
+            if crossing > u {
+                stalled = 0;
+            } else {
+                // Rays passing exactly through edges or corners may visit several voxels without
+                // advancing, but never more than meet at a corner
+                stalled += 1;
+                if stalled > MAX_STALLED_STEPS {
+                    return None;
+                }
+            }
+            u = crossing;
+            face = Some(axis_dir(axis, !positive));
+            if positive {
+                if voxel[axis] == SUBDIVISION_FACTOR - 1 {
+                    break (axis, positive);
+                }
+                voxel[axis] += 1;
+            } else {
+                if voxel[axis] == 0 {
+                    break (axis, positive);
+                }
+                voxel[axis] -= 1;
+            }
+        };
+
+        // Move into the neighboring chunk
+        let cursor = Cursor::from_vertex(chunk.node, chunk.vertex);
+        let next = cursor.step(graph, axis_dir(axis, positive))?;
+        // Stepping in the positive direction views the neighbor from across the side along the
+        // exit axis, and in the negative direction from across the new cube's side along it
+        let crossed = if positive {
+            cursor.sides()[axis]
+        } else {
+            next.sides()[axis]
+        };
+        let (node, vertex, transform) = next.canonicalize_with_transform(graph)?;
+        let transform = transform * crossed.reflection();
+        ray = (transform * ray.0, transform * ray.1);
+        chunk = ChunkId::new(node, vertex);
+        entered = true;
+    }
+}
+
+/// Limit on consecutive steps that don't advance along the ray, guarding against numerical trouble
+const MAX_STALLED_STEPS: u32 = 64;
+
+/// The direction of travel along `axis` of a cube's coordinates, as understood by `Cursor::step`
+fn axis_dir(axis: usize, positive: bool) -> Dir {
+    use Dir::*;
+    match (axis, positive) {
+        (0, true) => Forward,
+        (0, false) => Back,
+        (1, true) => Down,
+        (1, false) => Up,
+        (2, true) => Left,
+        (2, false) => Right,
+        _ => unreachable!("no such axis"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{dodeca::Side, graph::NodeId};
+    use approx::*;
+
+    /// A graph of empty chunks
+    fn empty_graph() -> Graph<(), VoxelData> {
+        let mut graph = Graph::new();
+        graph.ensure_nearby(NodeId::ROOT, 3);
+        let nodes = std::iter::once(NodeId::ROOT)
+            .chain(
+                graph
+                    .tree()
+                    .map(|(side, parent)| graph.neighbor(parent, side).unwrap()),
+            )
+            .collect::<Vec<_>>();
+        for node in nodes {
+            for vertex in graph.cubes_at(node) {
+                *graph.get_cube_mut(node, vertex) = Some(VoxelData::Empty);
+            }
+        }
+        graph
+    }
+
+    fn fill(graph: &mut Graph<(), VoxelData>, chunk: ChunkId, voxel: [usize; 3]) {
+        graph
+            .get_cube_mut(chunk.node, chunk.vertex)
+            .as_mut()
+            .unwrap()
+            .set(voxel, Material::Stone);
+    }
+
+    fn origin() -> Position {
+        Position {
+            node: NodeId::ROOT,
+            local: na::Matrix4::identity(),
+        }
+    }
+
+    /// Center of `voxel` in `chunk`, in the frame of the node `chunk` is viewed from
+    fn voxel_center(chunk: ChunkId, voxel: [usize; 3]) -> na::Vector4<f64> {
+        let coords = na::Vector3::from(voxel).map(|x| (x as f64 + 0.5) / SUBDIVISION_FACTOR as f64);
+        chunk.vertex.cube_to_node() * coords.push(1.0)
+    }
+
+    fn cast_at(
+        graph: &Graph<(), VoxelData>,
+        target: &na::Vector4<f64>,
+        max_distance: f64,
+    ) -> Option<RayHit> {
+        let direction = na::Unit::new_normalize(target.xyz().map(|x| x as f32));
+        raycast(graph, |x| x, &origin(), &direction, max_distance)
+    }
+
+    #[test]
+    fn empty() {
+        let graph = empty_graph();
+        for &direction in &[
+            na::Vector3::x(),
+            -na::Vector3::y(),
+            na::Vector3::new(1.0, 2.0, 3.0),
+            na::Vector3::new(-0.3, 0.1, -0.7),
+        ] {
+            let direction = na::Unit::new_normalize(direction);
+            assert!(raycast(&graph, |x| x, &origin(), &direction, 2.0).is_none());
+        }
+    }
+
+    #[test]
+    fn local_hit() {
+        let chunk = ChunkId::new(NodeId::ROOT, Vertex::A);
+        let voxel = [3, 4, 5];
+        let mut graph = empty_graph();
+        fill(&mut graph, chunk, voxel);
+        let target = voxel_center(chunk, voxel);
+        let hit = cast_at(&graph, &target, 2.0).expect("hit");
+        assert_eq!(hit.chunk, chunk);
+        assert_eq!(hit.voxel, voxel);
+        let center_distance = math::distance(&math::origin(), &target);
+        assert!(hit.distance < center_distance);
+        assert!(hit.distance > center_distance - 0.2);
+
+        // The point of impact lies on the face that was struck
+        let face = hit.face.expect("ray began outside the voxel");
+        let (axis, boundary) = match face {
+            Dir::Forward => (0, voxel[0] + 1),
+            Dir::Back => (0, voxel[0]),
+            Dir::Down => (1, voxel[1] + 1),
+            Dir::Up => (1, voxel[1]),
+            Dir::Left => (2, voxel[2] + 1),
+            Dir::Right => (2, voxel[2]),
+        };
+        let direction = math::lorentz_normalize(&target).xyz().normalize();
+        let impact = math::origin() + direction.push(0.0) * hit.distance.tanh();
+        let impact = chunk.vertex.node_to_cube() * impact;
+        assert_abs_diff_eq!(
+            impact[axis] / impact.w,
+            boundary as f64 / SUBDIVISION_FACTOR as f64,
+            epsilon = 1e-6
+        );
+    }
+
+    #[test]
+    fn distant_hit() {
+        // Aim at a voxel in a chunk belonging to another node, so the ray must cross several chunk
+        // and node boundaries to reach it
+        let mut graph = empty_graph();
+        let node = graph.neighbor(NodeId::ROOT, Side::A).unwrap();
+        let vertex = graph.cubes_at(node).next().unwrap();
+        let chunk = ChunkId::new(node, vertex);
+        let voxel = [6, 7, 8];
+        fill(&mut graph, chunk, voxel);
+        let target = Side::A.reflection() * voxel_center(chunk, voxel);
+
+        let hit = cast_at(&graph, &target, 5.0).expect("hit");
+        assert_eq!(hit.chunk, chunk);
+        assert_eq!(hit.voxel, voxel);
+        assert!(hit.distance < math::distance(&math::origin(), &target));
+
+        assert!(cast_at(&graph, &target, hit.distance - 1e-3).is_none());
+    }
+
+    #[test]
+    fn start_inside() {
+        let chunk = ChunkId::new(NodeId::ROOT, Vertex::A);
+        let mut graph = empty_graph();
+        fill(&mut graph, chunk, [0; 3]);
+        let target = voxel_center(chunk, [0; 3]);
+        let hit = cast_at(&graph, &target, 1.0).expect("hit");
+        assert_eq!(hit.voxel, [0; 3]);
+        assert!(hit.face.is_none());
+        assert_eq!(hit.distance, 0.0);
+    }
+}
@@ -0,0 +1,207 @@
+//! Logs of the traffic between a server and its clients, for later playback
+//!
+//! A recording begins with a header identifying the protocol version its messages are encoded
+//! in, followed by a sequence of timestamped entries, each concerning a single client.
+
+use std::{
+    io::{self, Read, Write},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    proto::{self, Command, ServerHello, Spawns, StateDelta},
+    EntityId,
+};
+
+/// Identifies a file as a recording
+const MAGIC: [u8; 8] = *b"hmrecord";
+
+/// Something that happened to the client controlling `character`, `time` after recording began
+#[derive(Debug, Deserialize)]
+pub struct Entry {
+    pub time: Duration,
+    pub character: EntityId,
+    pub event: Event,
+}
+
+#[derive(Debug, Deserialize)]
+pub enum Event {
+    /// The client connected as `name`
+    Hello { name: String, hello: ServerHello },
+    /// Sent by the server
+    Spawns(Spawns),
+    /// Sent by the server
+    StateDelta(StateDelta),
+    /// Received by the server
+    Command(Command),
+    /// The client disconnected
+    Lost,
+}
+
+/// Borrowed form of `Event`, which it must match variant for variant to be encoded identically
+#[derive(Serialize)]
+enum EventRef<'a> {
+    Hello {
+        name: &'a str,
+        hello: &'a ServerHello,
+    },
+    Spawns(&'a Spawns),
+    StateDelta(&'a StateDelta),
+    Command(&'a Command),
+    Lost,
+}
+
+pub struct Writer<W> {
+    out: W,
+    start: Instant,
+}
+
+impl<W: Write> Writer<W> {
+    /// Begin a recording, timestamped relative to now
+    pub fn new(mut out: W) -> io::Result<Self> {
+        out.write_all(&MAGIC)?;
+        out.write_all(&proto::PROTOCOL_VERSION.to_le_bytes())?;
+        Ok(Self {
+            out,
+            start: Instant::now(),
+        })
+    }
+
+    pub fn hello(&mut self, character: EntityId, name: &str, hello: &ServerHello) -> Result<()> {
+        self.write(character, EventRef::Hello { name, hello })
+    }
+
+    pub fn spawns(&mut self, character: EntityId, msg: &Spawns) -> Result<()> {
+        self.write(character, EventRef::Spawns(msg))
+    }
+
+    pub fn state_delta(&mut self, character: EntityId, msg: &StateDelta) -> Result<()> {
+        self.write(character, EventRef::StateDelta(msg))
+    }
+
+    pub fn command(&mut self, character: EntityId, msg: &Command) -> Result<()> {
+        self.write(character, EventRef::Command(msg))
+    }
+
+    pub fn lost(&mut self, character: EntityId) -> Result<()> {
+        self.write(character, EventRef::Lost)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    fn write(&mut self, character: EntityId, event: EventRef) -> Result<()> {
+        bincode::serialize_into(&mut self.out, &(self.start.elapsed(), character, event))?;
+        Ok(())
+    }
+}
+
+/// Iterator over the entries of a recording
+///
+/// A trailing partial entry, as left by a server that exited abruptly, is ignored.
+pub struct Reader<R> {
+    input: R,
+}
+
+impl<R: Read> Reader<R> {
+    pub fn new(mut input: R) -> Result<Self> {
+        let mut header = [0; MAGIC.len() + 2];
+        input
+            .read_exact(&mut header)
+            .context("reading recording header")?;
+        if header[..MAGIC.len()] != MAGIC {
+            bail!("not a recording");
+        }
+        let version = u16::from_le_bytes([header[MAGIC.len()], header[MAGIC.len() + 1]]);
+        if version != proto::PROTOCOL_VERSION {
+            bail!(
+                "recording uses protocol version {}; expected version {}",
+                version,
+                proto::PROTOCOL_VERSION
+            );
+        }
+        Ok(Self { input })
+    }
+}
+
+impl<R: Read> Iterator for Reader<R> {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Result<Entry>> {
+        match bincode::deserialize_from(&mut self.input) {
+            Ok(x) => Some(Ok(x)),
+            Err(e) => match *e {
+                bincode::ErrorKind::Io(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => None,
+                _ => Some(Err(e.into())),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::{Capabilities, MovementMode};
+
+    #[test]
+    fn roundtrip() {
+        let id = EntityId::from(42);
+        let mut writer = Writer::new(Vec::new()).unwrap();
+        let hello = ServerHello {
+            protocol_version: proto::PROTOCOL_VERSION,
+            capabilities: Capabilities::SUPPORTED,
+            character: id,
+            world_seed: 7,
+            rate: 10,
+        };
+        writer.hello(id, "alice", &hello).unwrap();
+        let command = Command {
+            step: 3,
+            node: crate::graph::NodeId::ROOT,
+            orientation: na::one(),
+            velocity: na::Vector3::x(),
+            mode: MovementMode::Walk,
+            jump: true,
+            crouch: false,
+        };
+        writer.command(id, &command).unwrap();
+        writer.lost(id).unwrap();
+        let mut data = writer.out;
+        // Truncate the last entry
+        data.pop();
+
+        let entries = Reader::new(&data[..])
+            .unwrap()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(entries.len(), 2);
+        assert!(entries.iter().all(|x| x.character == id));
+        assert!(entries[0].time <= entries[1].time);
+        match entries[0].event {
+            Event::Hello {
+                ref name,
+                ref hello,
+            } => {
+                assert_eq!(name, "alice");
+                assert_eq!(hello.world_seed, 7);
+            }
+            ref x => panic!("unexpected event {:?}", x),
+        }
+        match entries[1].event {
+            Event::Command(ref x) => {
+                assert_eq!(x.step, 3);
+                assert!(x.jump);
+            }
+            ref x => panic!("unexpected event {:?}", x),
+        }
+    }
+
+    #[test]
+    fn reject_foreign() {
+        assert!(Reader::new(&b"not a recording"[..]).is_err());
+    }
+}
@@ -0,0 +1,249 @@
+//! Client side of connections to a server, shared by graphical and headless clients
+
+use std::{
+    fmt,
+    net::{SocketAddr, ToSocketAddrs},
+};
+
+use anyhow::{anyhow, Context, Error, Result};
+use futures_util::StreamExt;
+
+use crate::{codec, proto};
+
+/// A connection to a server that's completed the handshake
+pub struct Session {
+    /// Must outlive `connection`
+    pub endpoint: quinn::Endpoint,
+    pub connection: quinn::Connection,
+    /// Streams opened by the server after the ordered stream, each carrying an unordered message
+    pub uni_streams: quinn::IncomingUniStreams,
+    /// Stream carrying the server's ordered messages, following `hello`
+    pub ordered: quinn::RecvStream,
+    pub hello: proto::ServerHello,
+}
+
+/// Connect to the server at `address`, whose certificate must name `server_name`, and introduce
+/// ourselves with `hello`
+pub async fn connect(
+    address: &str,
+    server_name: &str,
+    client_cfg: quinn::ClientConfig,
+    hello: &proto::ClientHello,
+) -> Result<Session> {
+    let server = address
+        .to_socket_addrs()
+        .with_context(|| format!("resolving {}", address))?
+        .next()
+        .ok_or_else(|| anyhow!("no addresses found for {}", address))?;
+    // Bind an address of the same family as the server's
+    let local: SocketAddr = if server.is_ipv6() {
+        "[::]:0".parse().unwrap()
+    } else {
+        "0.0.0.0:0".parse().unwrap()
+    };
+    let mut endpoint = quinn::Endpoint::builder();
+    endpoint.default_client_config(client_cfg);
+    let (endpoint, _) = endpoint.bind(&local)?;
+
+    let quinn::NewConnection {
+        connection,
+        mut uni_streams,
+        ..
+    } = endpoint
+        .connect(&server, server_name)
+        .with_context(|| format!("connecting to {}", address))?
+        .await
+        .with_context(|| format!("connecting to {}", address))?;
+
+    // Send our hello message on the first stream
+    codec::send_whole(connection.open_uni().await?, hello).await?;
+
+    // Receive the server's hello message
+    let mut ordered = uni_streams
+        .next()
+        .await
+        .ok_or_else(|| anyhow!("connection closed unexpectedly"))??;
+    let hello = codec::recv::<proto::ServerHello>(&mut ordered)
+        .await?
+        .ok_or_else(|| anyhow!("ordered stream closed unexpectedly"))?;
+    if hello.protocol_version != proto::PROTOCOL_VERSION {
+        return Err(IncompatibleVersion {
+            server: hello.protocol_version,
+        }
+        .into());
+    }
+    Ok(Session {
+        endpoint,
+        connection,
+        uni_streams,
+        ordered,
+        hello,
+    })
+}
+
+/// The server speaks a protocol version the client doesn't
+#[derive(Debug, Copy, Clone)]
+pub struct IncompatibleVersion {
+    pub server: u16,
+}
+
+impl fmt::Display for IncompatibleVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "incompatible protocol version {}; client requires version {}",
+            self.server,
+            proto::PROTOCOL_VERSION
+        )
+    }
+}
+
+impl std::error::Error for IncompatibleVersion {}
+
+/// Why a connection ended
+#[derive(Debug)]
+pub enum Disconnect {
+    /// The server closed the connection deliberately
+    Closed {
+        reason: proto::DisconnectReason,
+        details: String,
+    },
+    /// We refused the server, e.g. for an incompatible protocol version or an untrusted certificate
+    Refused(Error),
+    /// Anything else went wrong
+    Error(Error),
+}
+
+impl Disconnect {
+    /// Determine why `error` ended a connection
+    pub fn new(error: Error) -> Self {
+        if error.chain().any(refused) {
+            return Disconnect::Refused(error);
+        }
+        let close = error
+            .chain()
+            .find_map(|x| match x.downcast_ref::<quinn::ConnectionError>() {
+                Some(quinn::ConnectionError::ApplicationClosed(close)) => Some(close),
+                _ => None,
+            });
+        let close = match close {
+            Some(x) => x,
+            None => return Disconnect::Error(error),
+        };
+        match proto::DisconnectReason::from_code(u64::from(close.error_code)) {
+            Some(reason) => Disconnect::Closed {
+                reason,
+                details: String::from_utf8_lossy(&close.reason).into(),
+            },
+            None => Disconnect::Error(error),
+        }
+    }
+
+    /// The server's reason for closing the connection, if any
+    pub fn reason(&self) -> Option<proto::DisconnectReason> {
+        match *self {
+            Disconnect::Closed { reason, .. } => Some(reason),
+            Disconnect::Refused(_) | Disconnect::Error(_) => None,
+        }
+    }
+
+    /// Whether trying again might succeed
+    pub fn should_reconnect(&self) -> bool {
+        use proto::DisconnectReason::*;
+        match *self {
+            Disconnect::Refused(_) => false,
+            Disconnect::Closed { reason, .. } => match reason {
+                VersionMismatch | Kicked | Rejected => false,
+                _ => true,
+            },
+            Disconnect::Error(_) => true,
+        }
+    }
+}
+
+/// Whether `error` indicates that we refused the server
+fn refused(error: &(dyn std::error::Error + 'static)) -> bool {
+    if error.is::<IncompatibleVersion>() {
+        return true;
+    }
+    match error.downcast_ref::<quinn::ConnectionError>() {
+        // Raised locally, so our certificate verifier rejected the server
+        Some(quinn::ConnectionError::TransportError(e)) => is_bad_certificate(u64::from(e.code)),
+        _ => false,
+    }
+}
+
+/// Whether `code` is the QUIC transport error code for a TLS bad_certificate alert
+fn is_bad_certificate(code: u64) -> bool {
+    // QUIC carries TLS alerts as transport errors 0x100 through 0x1ff
+    const BAD_CERTIFICATE: u64 = 0x100 | 42;
+    code == BAD_CERTIFICATE
+}
+
+impl fmt::Display for Disconnect {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Disconnect::Closed {
+                reason,
+                ref details,
+            } => {
+                if details.is_empty() {
+                    write!(f, "{}", reason)
+                } else {
+                    write!(f, "{}: {}", reason, details)
+                }
+            }
+            Disconnect::Refused(ref e) | Disconnect::Error(ref e) => write!(f, "{:#}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refusals_are_final() {
+        let error = Error::new(IncompatibleVersion { server: 1 }).context("connecting");
+        let disconnect = Disconnect::new(error);
+        match disconnect {
+            Disconnect::Refused(_) => {}
+            ref x => panic!("unexpected {:?}", x),
+        }
+        assert!(!disconnect.should_reconnect());
+        assert_eq!(
+            disconnect.to_string(),
+            format!(
+                "connecting: incompatible protocol version 1; client requires version {}",
+                proto::PROTOCOL_VERSION
+            )
+        );
+        let closed = |reason| Disconnect::Closed {
+            reason,
+            details: String::new(),
+        };
+        assert!(!closed(proto::DisconnectReason::Rejected).should_reconnect());
+        assert!(closed(proto::DisconnectReason::AlreadyConnected).should_reconnect());
+    }
+
+    #[test]
+    fn other_errors_are_retried() {
+        let disconnect = Disconnect::new(anyhow!("connection closed unexpectedly"));
+        match disconnect {
+            Disconnect::Error(_) => {}
+            ref x => panic!("unexpected {:?}", x),
+        }
+        assert!(disconnect.should_reconnect());
+        let disconnect = Disconnect::new(quinn::ConnectionError::TimedOut.into());
+        assert!(disconnect.should_reconnect());
+    }
+
+    #[test]
+    fn bad_certificate() {
+        assert!(is_bad_certificate(0x12a));
+        // handshake_failure
+        assert!(!is_bad_certificate(0x128));
+        // PROTOCOL_VIOLATION
+        assert!(!is_bad_certificate(0xa));
+    }
+}
@@ -1,6 +1,13 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{dodeca::Vertex, graph::NodeId};
+
 pub const SUBDIVISION_FACTOR: usize = 12;
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+/// Maximum distance from a character to the center of a voxel it may modify
+pub const BLOCK_REACH: f64 = 2.0;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize, Deserialize)]
 #[repr(u16)]
 pub enum Material {
     Void = 0,
@@ -18,3 +25,77 @@ impl Default for Material {
         Material::Void
     }
 }
+
+/// Identifies the chunk of voxels filling the cube dual to `vertex` of `node`
+///
+/// Only meaningful for canonical pairs, i.e. those enumerated by `Graph::cubes_at`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub struct ChunkId {
+    pub node: NodeId,
+    pub vertex: Vertex,
+}
+
+impl ChunkId {
+    pub fn new(node: NodeId, vertex: Vertex) -> Self {
+        Self { node, vertex }
+    }
+}
+
+/// Contents of a chunk
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum VoxelData {
+    /// Every voxel is `Material::Void`
+    Empty,
+    /// One material per voxel, laid out as described by `voxel_index`
+    Dense(Box<[Material]>),
+}
+
+impl VoxelData {
+    /// Material of the voxel at `coords`, each of which must be less than `SUBDIVISION_FACTOR`
+    pub fn get(&self, coords: [usize; 3]) -> Material {
+        match *self {
+            VoxelData::Empty => Material::Void,
+            VoxelData::Dense(ref data) => data[voxel_index(coords)],
+        }
+    }
+
+    /// Overwrite the voxel at `coords`, converting to a dense representation if necessary
+    pub fn set(&mut self, coords: [usize; 3], material: Material) {
+        self.data_mut()[voxel_index(coords)] = material;
+    }
+
+    /// Access the dense representation, allocating it if necessary
+    pub fn data_mut(&mut self) -> &mut [Material] {
+        if let VoxelData::Empty = *self {
+            *self = VoxelData::Dense(
+                vec![Material::Void; (SUBDIVISION_FACTOR + 2).pow(3)].into_boxed_slice(),
+            );
+        }
+        match *self {
+            VoxelData::Dense(ref mut data) => data,
+            VoxelData::Empty => unreachable!(),
+        }
+    }
+}
+
+/// Coordinates of the voxel containing the point at `coords` in the chunk's cube-local space
+///
+/// Points slightly outside the chunk are attributed to the nearest voxel.
+pub fn voxel_at(coords: &na::Vector3<f64>) -> [usize; 3] {
+    let index = |x: f64| {
+        ((x * SUBDIVISION_FACTOR as f64).floor().max(0.0) as usize).min(SUBDIVISION_FACTOR - 1)
+    };
+    [index(coords.x), index(coords.y), index(coords.z)]
+}
+
+/// Index into dense voxel data of the voxel at `coords`
+///
+/// Dense data includes a margin of one voxel on every side of the chunk, so that surfaces can be
+/// extracted without consulting neighboring chunks.
+#[inline]
+pub fn voxel_index(coords: [usize; 3]) -> usize {
+    debug_assert!(coords.iter().all(|&x| x < SUBDIVISION_FACTOR));
+    (coords[0] + 1)
+        + (coords[1] + 1) * (SUBDIVISION_FACTOR + 2)
+        + (coords[2] + 1) * (SUBDIVISION_FACTOR + 2).pow(2)
+}
@@ -0,0 +1,443 @@
+//! Deterministic procedural generation of world contents
+//!
+//! Everything generated here is a pure function of the world seed and of the path from the root
+//! node to the node being generated, so every peer produces identical contents regardless of the
+//! order in which it discovered the graph.
+//!
+//! Terrain is shaped relative to a ground plane passing just below the root node. Each node carries
+//! samples of a few noise fields, which are interpolated across the chunks having that node as a
+//! corner to produce fields that vary smoothly across chunk and node boundaries.
+
+use crate::{
+    dodeca::{Side, Vertex},
+    graph::{Graph, NodeId},
+    math,
+    world::{voxel_index, ChunkId, Material, VoxelData, SUBDIVISION_FACTOR},
+};
+
+/// Per-node generation state, derived from the node's parent
+#[derive(Debug, Clone, PartialEq)]
+pub struct NodeState {
+    /// Digest of the world seed and the path from the root to this node
+    hash: u64,
+    /// Digest of the world seed alone, for features shared by the entire world
+    world_hash: u64,
+    /// The ground plane, in this node's frame
+    surface: Plane,
+    /// Height of the terrain above the ground plane near this node
+    elevation: f64,
+    /// Sample of the field whose zero set traces out cave tunnels
+    caves: f64,
+}
+
+impl NodeState {
+    pub fn root(seed: u64) -> Self {
+        Self {
+            hash: mix(seed),
+            world_hash: combine(seed, 0),
+            surface: Plane::below_origin(ROOT_HEIGHT),
+            elevation: 0.0,
+            // Keep tunnels away from the spawn point
+            caves: 1.0,
+        }
+    }
+
+    /// State of the node reached from this one across `side`, away from the root
+    pub fn child(&self, side: Side) -> Self {
+        let hash = combine(self.hash, side as u64);
+        Self {
+            hash,
+            world_hash: self.world_hash,
+            surface: self.surface.reflect(side),
+            elevation: self.elevation * ELEVATION_PERSISTENCE
+                + (unit(combine(hash, 1)) - 0.5) * ELEVATION_VARIATION,
+            caves: unit(combine(hash, 2)) * 2.0 - 1.0,
+        }
+    }
+
+    /// Unit normal of the ground plane in this node's frame, pointing away from the ground
+    pub fn up(&self) -> &na::Vector4<f64> {
+        &self.surface.normal
+    }
+
+    /// Compute the state of the node at the end of `path`, a sequence of sides leading away from
+    /// the root
+    pub fn from_path(seed: u64, path: impl IntoIterator<Item = Side>) -> Self {
+        path.into_iter()
+            .fold(Self::root(seed), |state, side| state.child(side))
+    }
+
+    /// Compute the state of the non-root node `node` from that of its parent, which must already
+    /// be populated
+    pub fn from_parent<C>(graph: &Graph<NodeState, C>, node: NodeId) -> Self {
+        let side = graph.parent(node).expect("root node has no parent");
+        graph
+            .get(graph.neighbor(node, side).unwrap())
+            .as_ref()
+            .expect("parent state unpopulated")
+            .child(side)
+    }
+}
+
+/// Everything needed to generate a chunk
+///
+/// Gathered from the states of every node at a corner of the chunk, so a chunk can only be
+/// generated once all of them are known.
+pub struct ChunkParams {
+    vertex: Vertex,
+    world_hash: u64,
+    surface: Plane,
+    /// Per-corner samples, indexed by `4 * x + 2 * y + z` for the corner at chunk coordinates
+    /// `(x, y, z)`
+    elevation: [f64; 8],
+    caves: [f64; 8],
+}
+
+impl ChunkParams {
+    /// Gather the parameters for `chunk`, if the states of all of its corners are known
+    pub fn new<C>(graph: &Graph<NodeState, C>, chunk: ChunkId) -> Option<Self> {
+        let state = graph.get(chunk.node).as_ref()?;
+        let mut elevation = [0.0; 8];
+        let mut caves = [0.0; 8];
+        // `dual_vertices` enumerates corners in the order described on `elevation`
+        for (i, mut path) in chunk.vertex.dual_vertices().enumerate() {
+            let corner = path.try_fold(chunk.node, |node, side| graph.neighbor(node, side))?;
+            let corner = graph.get(corner).as_ref()?;
+            elevation[i] = corner.elevation;
+            caves[i] = corner.caves;
+        }
+        Some(Self {
+            vertex: chunk.vertex,
+            world_hash: state.world_hash,
+            surface: state.surface,
+            elevation,
+            caves,
+        })
+    }
+
+    /// Generate the initial contents of the chunk
+    pub fn generate(&self) -> VoxelData {
+        let mut voxels = VoxelData::Empty;
+        let to_node = self.vertex.cube_to_node();
+        for z in 0..SUBDIVISION_FACTOR {
+            for y in 0..SUBDIVISION_FACTOR {
+                for x in 0..SUBDIVISION_FACTOR {
+                    let coords = na::Vector3::new(x, y, z)
+                        .map(|i| (i as f64 + 0.5) / SUBDIVISION_FACTOR as f64);
+                    let material = self.material_at(&coords, &(to_node * coords.push(1.0)));
+                    if material != Material::Void {
+                        voxels.data_mut()[voxel_index([x, y, z])] = material;
+                    }
+                }
+            }
+        }
+        voxels
+    }
+
+    /// Material at chunk coordinates `coords`, which lie at `p` in the chunk's node
+    fn material_at(&self, coords: &na::Vector3<f64>, p: &na::Vector4<f64>) -> Material {
+        let height = self.surface.distance_to(p);
+        let depth = trilerp(&self.elevation, coords) - height;
+        if depth < 0.0 {
+            return Material::Void;
+        }
+        if depth > CAVE_MIN_DEPTH && trilerp(&self.caves, coords).abs() < CAVE_RADIUS {
+            return Material::Void;
+        }
+        if depth < TOPSOIL_DEPTH {
+            return if height < SHORE_HEIGHT {
+                Material::Sand
+            } else {
+                Material::Dirt
+            };
+        }
+        let stratum = (height / STRATUM_THICKNESS).floor() as i64;
+        match combine(self.world_hash, stratum as u64) % 5 {
+            0 => Material::Sand,
+            1 => Material::Dirt,
+            _ => Material::Stone,
+        }
+    }
+}
+
+/// Height of the root node's center above the ground plane
+const ROOT_HEIGHT: f64 = 2.0;
+/// Fraction of a node's elevation inherited by its children
+const ELEVATION_PERSISTENCE: f64 = 0.9;
+/// Range of the random change in elevation between a node and its children
+const ELEVATION_VARIATION: f64 = 1.0;
+/// Minimum depth below the terrain surface at which tunnels may appear
+const CAVE_MIN_DEPTH: f64 = 0.5;
+/// Magnitude of the cave field below which voxels are carved away
+const CAVE_RADIUS: f64 = 0.08;
+/// Depth of the soil covering the terrain
+const TOPSOIL_DEPTH: f64 = 0.25;
+/// Height below which the terrain surface is sand rather than dirt
+const SHORE_HEIGHT: f64 = -0.5;
+/// Thickness of each horizontal layer of rock
+const STRATUM_THICKNESS: f64 = 0.3;
+
+/// A plane in H^3, represented by its unit spacelike normal
+#[derive(Debug, Copy, Clone, PartialEq)]
+struct Plane {
+    normal: na::Vector4<f64>,
+}
+
+impl Plane {
+    /// The plane whose normal is +Y at the origin, `height` below it
+    fn below_origin(height: f64) -> Self {
+        Self {
+            normal: na::Vector4::new(0.0, height.cosh(), 0.0, -height.sinh()),
+        }
+    }
+
+    /// The same plane, in the frame of the neighbor across `side`
+    fn reflect(&self, side: Side) -> Self {
+        let normal = side.reflection() * self.normal;
+        // Renormalize to keep rounding error from accumulating along long paths
+        Self {
+            normal: normal / math::mip(&normal, &normal).sqrt(),
+        }
+    }
+
+    /// Signed distance from the plane to `p`, positive on the side the normal points towards
+    fn distance_to(&self, p: &na::Vector4<f64>) -> f64 {
+        (math::mip(&self.normal, p) / (-math::mip(p, p)).sqrt()).asinh()
+    }
+}
+
+/// Trilinearly interpolate between corner samples ordered as in `ChunkParams`
+fn trilerp(corners: &[f64; 8], coords: &na::Vector3<f64>) -> f64 {
+    let mut result = 0.0;
+    for (i, &value) in corners.iter().enumerate() {
+        let weight = |bit: usize, t: f64| if i & bit != 0 { t } else { 1.0 - t };
+        result += value * weight(4, coords.x) * weight(2, coords.y) * weight(1, coords.z);
+    }
+    result
+}
+
+/// Map a hash to a uniformly distributed value in [0, 1)
+fn unit(hash: u64) -> f64 {
+    (hash >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// The splitmix64 finalizer; a fast, portable, well-distributed bijection
+fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+fn combine(a: u64, b: u64) -> u64 {
+    mix(a ^ mix(b.wrapping_add(0x9e37_79b9_7f4a_7c15)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cursor::Cursor, proto::Position};
+
+    const SEED: u64 = 0x1234_5678;
+
+    fn path<N, C>(graph: &Graph<N, C>, mut node: NodeId) -> Vec<Side> {
+        let mut path = Vec::new();
+        while let Some(side) = graph.parent(node) {
+            path.push(side);
+            node = graph.neighbor(node, side).unwrap();
+        }
+        path.reverse();
+        path
+    }
+
+    fn populate(graph: &mut Graph<NodeState, ()>) {
+        if graph.get(NodeId::ROOT).is_none() {
+            *graph.get_mut(NodeId::ROOT) = Some(NodeState::root(SEED));
+        }
+        for i in 0..graph.fresh().len() {
+            let node = graph.fresh()[i];
+            *graph.get_mut(node) = Some(NodeState::from_parent(graph, node));
+        }
+        graph.clear_fresh();
+    }
+
+    fn voxels(chunk: &VoxelData) -> impl Iterator<Item = Material> + '_ {
+        (0..SUBDIVISION_FACTOR.pow(3)).map(move |i| {
+            chunk.get([
+                i % SUBDIVISION_FACTOR,
+                (i / SUBDIVISION_FACTOR) % SUBDIVISION_FACTOR,
+                i / SUBDIVISION_FACTOR.pow(2),
+            ])
+        })
+    }
+
+    #[test]
+    fn state_matches_path() {
+        let mut graph = Graph::new();
+        graph.ensure_nearby(NodeId::ROOT, 3);
+        populate(&mut graph);
+        let nodes = std::iter::once(NodeId::ROOT)
+            .chain(
+                graph
+                    .tree()
+                    .map(|(side, parent)| graph.neighbor(parent, side).unwrap()),
+            )
+            .collect::<Vec<_>>();
+        for node in nodes {
+            assert_eq!(
+                graph.get(node).as_ref().unwrap(),
+                &NodeState::from_path(SEED, path(&graph, node))
+            );
+        }
+    }
+
+    #[test]
+    fn generation_independent_of_creation_order() {
+        let mut a = Graph::new();
+        a.ensure_nearby(NodeId::ROOT, 4);
+        populate(&mut a);
+
+        // `ensure_nearby` explores the last side first, so grow a second graph in the opposite
+        // order, such that nodes with several shorter neighbors are first reached from another one.
+        let mut b = Graph::new();
+        for first in Side::iter() {
+            let node = b.ensure_neighbor(NodeId::ROOT, first);
+            for second in Side::iter() {
+                b.ensure_neighbor(node, second);
+            }
+        }
+        b.ensure_nearby(NodeId::ROOT, 4);
+        populate(&mut b);
+
+        let mut compared = 0;
+        let a_nodes = std::iter::once(NodeId::ROOT).chain(
+            a.tree()
+                .map(|(side, parent)| a.neighbor(parent, side).unwrap()),
+        );
+        for a_node in a_nodes {
+            let path = path(&a, a_node);
+            let b_node = path
+                .iter()
+                .try_fold(NodeId::ROOT, |node, &side| b.neighbor(node, side));
+            let b_node = match b_node {
+                None => continue,
+                Some(x) => x,
+            };
+            assert_eq!(path, self::path(&b, b_node), "canonical paths agree");
+            for vertex in a.cubes_at(a_node) {
+                let a_params = ChunkParams::new(&a, ChunkId::new(a_node, vertex));
+                let b_params = ChunkParams::new(&b, ChunkId::new(b_node, vertex));
+                let (a_chunk, b_chunk) = match (a_params, b_params) {
+                    (Some(x), Some(y)) => (x.generate(), y.generate()),
+                    _ => continue,
+                };
+                assert!(voxels(&a_chunk).eq(voxels(&b_chunk)));
+                compared += 1;
+            }
+        }
+        assert!(compared > 100, "{}", compared);
+    }
+
+    #[test]
+    fn seed_matters() {
+        let chunk = |seed| {
+            let mut graph = Graph::new();
+            *graph.get_mut(NodeId::ROOT) = Some(NodeState::root(seed));
+            graph.ensure_nearby(NodeId::ROOT, 4);
+            populate(&mut graph);
+            let origin = Position {
+                node: NodeId::ROOT,
+                local: na::Matrix4::identity(),
+            };
+            graph
+                .nearby_cubes(origin, 3.0)
+                .into_iter()
+                .filter_map(|(node, vertex, _, _)| {
+                    ChunkParams::new(&graph, ChunkId::new(node, vertex))
+                })
+                .map(|params| voxels(&params.generate()).collect::<Vec<_>>())
+                .collect::<Vec<_>>()
+        };
+        assert_ne!(chunk(1), chunk(2));
+    }
+
+    #[test]
+    fn spawn_point_clear() {
+        let mut graph = Graph::new();
+        graph.ensure_nearby(NodeId::ROOT, 3);
+        populate(&mut graph);
+        for vertex in graph.cubes_at(NodeId::ROOT) {
+            let chunk = ChunkParams::new(&graph, ChunkId::new(NodeId::ROOT, vertex))
+                .unwrap()
+                .generate();
+            // The voxel nearest the root's center
+            assert_eq!(chunk.get([0, 0, 0]), Material::Void);
+        }
+    }
+
+    #[test]
+    fn underground_solid() {
+        let mut graph = Graph::new();
+        graph.ensure_nearby(NodeId::ROOT, 2);
+        populate(&mut graph);
+
+        // Repeatedly descend to whichever neighbor lies furthest below the ground plane
+        let mut node = NodeId::ROOT;
+        for _ in 0..4 {
+            let surface = graph.get(node).as_ref().unwrap().surface;
+            let height = |side: Side| surface.distance_to(&(side.reflection() * math::origin()));
+            let side = Side::iter()
+                .min_by(|&a, &b| height(a).partial_cmp(&height(b)).unwrap())
+                .unwrap();
+            node = graph.ensure_neighbor(node, side);
+            graph.ensure_nearby(node, 3);
+            populate(&mut graph);
+        }
+        let surface = graph.get(node).as_ref().unwrap().surface;
+        assert!(surface.distance_to(&math::origin()) < -2.0);
+
+        let mut solid = 0;
+        let mut total = 0;
+        for vertex in Vertex::iter() {
+            let (chunk_node, chunk_vertex) = Cursor::from_vertex(node, vertex)
+                .canonicalize(&graph)
+                .unwrap();
+            let chunk = ChunkParams::new(&graph, ChunkId::new(chunk_node, chunk_vertex))
+                .unwrap()
+                .generate();
+            for material in voxels(&chunk) {
+                total += 1;
+                if material != Material::Void {
+                    solid += 1;
+                }
+            }
+        }
+        assert!(solid * 10 > total * 7, "{} of {} solid", solid, total);
+    }
+
+    #[test]
+    fn terrain_has_surface() {
+        let mut graph = Graph::new();
+        graph.ensure_nearby(NodeId::ROOT, 4);
+        populate(&mut graph);
+        let origin = Position {
+            node: NodeId::ROOT,
+            local: na::Matrix4::identity(),
+        };
+        let mut void = 0;
+        let mut solid = 0;
+        for (node, vertex, _, _) in graph.nearby_cubes(origin, 3.0) {
+            let params = match ChunkParams::new(&graph, ChunkId::new(node, vertex)) {
+                None => continue,
+                Some(x) => x,
+            };
+            for material in voxels(&params.generate()) {
+                if material == Material::Void {
+                    void += 1;
+                } else {
+                    solid += 1;
+                }
+            }
+        }
+        assert!(void > 0 && solid > 0);
+    }
+}
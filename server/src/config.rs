@@ -15,6 +15,19 @@ pub struct Config {
     pub listen: SocketAddr,
     pub rate: u16,
     pub view_distance: u32,
+    /// Maximum number of simultaneously connected clients; unlimited if unset
+    pub max_clients: Option<u32>,
+    /// Seed for world generation; chosen randomly if unset. Ignored when loading a saved world.
+    pub seed: Option<u64>,
+    /// Directory to save the world to and load it from; the world is lost on exit if unset
+    ///
+    /// Also holds the self-signed certificate generated when `certificate_chain` and `private_key`
+    /// are unset, which is otherwise kept in the user's data directory. Delete it to generate a new
+    /// one, e.g. after changing `server_name`.
+    pub save_directory: Option<PathBuf>,
+    /// File to record all traffic with clients to, for playback by a client; nothing is recorded
+    /// if unset
+    pub record: Option<PathBuf>,
 }
 
 impl Config {
@@ -35,6 +48,10 @@ impl Default for Config {
             listen: SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 1234),
             rate: 10,
             view_distance: 3,
+            max_clients: None,
+            seed: None,
+            save_directory: None,
+            record: None,
         }
     }
 }
@@ -1,121 +1,314 @@
 mod config;
+mod persistence;
 mod sim;
 
-use std::{fs, path::Path, sync::Arc, time::Duration};
+use std::{
+    fs,
+    io::{self, BufWriter, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 
 use anyhow::{anyhow, Context, Error, Result};
-use futures::{select, StreamExt, TryStreamExt};
+use futures::{future, pin_mut, select, stream, Future, FutureExt, StreamExt, TryStreamExt};
 use hecs::Entity;
 use quinn::{Certificate, CertificateChain, PrivateKey};
 use slotmap::DenseSlotMap;
-use tokio::sync::mpsc;
-use tracing::{error, error_span, info, trace, warn};
+use tokio::{sync::mpsc, task::JoinHandle};
+use tracing::{debug, error, error_span, info, trace, warn};
 
-use common::{codec, proto};
-use config::Config;
-use sim::Sim;
+use common::{codec, proto, proto::DisconnectReason, recording, EntityId};
+pub use config::Config;
+use sim::{Baseline, Sim, SpawnError};
 
+/// Run a server until `shutdown` completes
 #[tokio::main]
-pub async fn run() -> Result<()> {
-    let cfg = match std::env::args_os().nth(1) {
-        Some(path) => Config::load(Path::new(&path))?,
-        None => Config::default(),
+pub async fn run(cfg: Config, shutdown: impl Future<Output = ()>) -> Result<()> {
+    Server::new(cfg)?.run(shutdown).await;
+    Ok(())
+}
+
+/// Directory in which to store a self-signed certificate: the save directory if there is one, or
+/// else a per-user data directory shared by every server without one
+fn certificate_directory(cfg: &Config) -> Result<PathBuf> {
+    if let Some(ref dir) = cfg.save_directory {
+        return Ok(dir.clone());
+    }
+    let dirs = directories::ProjectDirs::from("", "", "hypermine")
+        .ok_or_else(|| anyhow!("couldn't find a home directory to store the certificate in"))?;
+    Ok(dirs.data_dir().join("server"))
+}
+
+/// Completes when the process is asked to terminate by SIGINT (e.g. Ctrl+C) or, on Unix, SIGTERM
+pub async fn termination() {
+    let interrupt = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("failed to listen for SIGINT: {}", e);
+            future::pending::<()>().await;
+        }
     };
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut x) => {
+                x.recv().await;
+            }
+            Err(e) => {
+                error!("failed to listen for SIGTERM: {}", e);
+                future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = future::pending::<()>();
+    pin_mut!(interrupt, terminate);
+    future::select(interrupt, terminate).await;
+}
 
-    let (certs, pkey) = match (&cfg.certificate_chain, &cfg.private_key) {
-        (&Some(ref certificate_chain), &Some(ref private_key)) => (
-            CertificateChain::from_pem(
-                &fs::read(certificate_chain).context("reading certificate chain")?,
-            )
-            .context("parsing certificate chain")?,
-            PrivateKey::from_pem(&fs::read(private_key).context("reading private key")?)
-                .context("parsing private key")?,
-        ),
-        _ => {
-            // TODO: Cache on disk
-            warn!("generating self-signed certificate");
-            let cert = rcgen::generate_simple_self_signed(vec![cfg
-                .server_name
-                .clone()
-                .map(Ok)
-                .unwrap_or_else(|| {
-                    hostname::get().context("getting hostname").and_then(|x| {
-                        x.into_string()
-                            .map_err(|_| anyhow!("hostname is not valid UTF-8"))
-                    })
-                })?])
-            .unwrap();
-            let key = cert.serialize_private_key_der();
-            let cert = cert.serialize_der().unwrap();
-            (
-                CertificateChain::from_certs(Certificate::from_der(&cert)),
-                PrivateKey::from_der(&key).unwrap(),
-            )
+/// Load the self-signed certificate and private key stored in `dir`, generating and storing them
+/// if necessary
+///
+/// Reusing the certificate allows clients that pinned it on first connection to recognize us again.
+fn cached_certificate(cfg: &Config, dir: &Path) -> Result<(Vec<u8>, Vec<u8>)> {
+    let cert_path = dir.join("certificate.der");
+    let key_path = dir.join("private_key.der");
+    match fs::read(&cert_path) {
+        Ok(cert) => {
+            let key = fs::read(&key_path).context("reading private key")?;
+            return Ok((cert, key));
         }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e).context("reading certificate"),
+    }
+    let (cert, key) = generate_certificate(cfg)?;
+    fs::create_dir_all(dir).context("creating save directory")?;
+    // Write the key first so that a certificate is never stored without it
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options
+        .open(&key_path)
+        .and_then(|mut file| file.write_all(&key))
+        .context("writing private key")?;
+    fs::write(&cert_path, &cert).context("writing certificate")?;
+    info!(path = %cert_path.display(), "stored certificate");
+    Ok((cert, key))
+}
+
+/// Generate a self-signed certificate and private key, both DER-encoded
+fn generate_certificate(cfg: &Config) -> Result<(Vec<u8>, Vec<u8>)> {
+    warn!("generating self-signed certificate");
+    let name = match cfg.server_name {
+        Some(ref x) => x.clone(),
+        None => hostname::get()
+            .context("getting hostname")?
+            .into_string()
+            .map_err(|_| anyhow!("hostname is not valid UTF-8"))?,
     };
-    let mut server_config = quinn::ServerConfigBuilder::default();
-    server_config
-        .certificate(certs, pkey)
-        .context("parsing certificate")?;
-    let mut endpoint = quinn::Endpoint::builder();
-    endpoint.listen(server_config.build());
-    let (endpoint, incoming) = endpoint.bind(&cfg.listen)?;
-    info!(address = %endpoint.local_addr().unwrap(), "listening");
-
-    let server = Server::new(cfg);
-    server.run(incoming).await;
-    Ok(())
+    let cert = rcgen::generate_simple_self_signed(vec![name]).unwrap();
+    let key = cert.serialize_private_key_der();
+    let cert = cert.serialize_der().unwrap();
+    Ok((cert, key))
+}
+
+/// A server listening for connections
+///
+/// Network I/O is performed by background tasks, so a server must be created and used within a
+/// Tokio runtime.
+pub struct Server {
+    endpoint: quinn::Endpoint,
+    local_addr: SocketAddr,
+    incoming: stream::Fuse<stream::BoxStream<'static, IncomingConnection>>,
+    client_events_send: mpsc::Sender<(ClientId, ClientEvent)>,
+    client_events: stream::Fuse<mpsc::Receiver<(ClientId, ClientEvent)>>,
+    state: State,
+}
+
+type IncomingConnection = Result<quinn::NewConnection, quinn::ConnectionError>;
+
+impl Server {
+    /// Start listening for connections, loading the world from `cfg.save_directory` if possible
+    pub fn new(cfg: Config) -> Result<Self> {
+        let (certs, pkey) = match (&cfg.certificate_chain, &cfg.private_key) {
+            (&Some(ref certificate_chain), &Some(ref private_key)) => (
+                CertificateChain::from_pem(
+                    &fs::read(certificate_chain).context("reading certificate chain")?,
+                )
+                .context("parsing certificate chain")?,
+                PrivateKey::from_pem(&fs::read(private_key).context("reading private key")?)
+                    .context("parsing private key")?,
+            ),
+            _ => {
+                let (cert, key) = cached_certificate(&cfg, &certificate_directory(&cfg)?)?;
+                (
+                    CertificateChain::from_certs(std::iter::once(
+                        Certificate::from_der(&cert).context("parsing certificate")?,
+                    )),
+                    PrivateKey::from_der(&key).context("parsing private key")?,
+                )
+            }
+        };
+        let mut server_config = quinn::ServerConfigBuilder::default();
+        server_config
+            .certificate(certs, pkey)
+            .context("parsing certificate")?;
+        let mut endpoint = quinn::Endpoint::builder();
+        endpoint.listen(server_config.build());
+        let (endpoint, incoming) = endpoint.bind(&cfg.listen)?;
+        let local_addr = endpoint.local_addr().context("getting local address")?;
+        info!(address = %local_addr, "listening");
+
+        let save = match cfg.save_directory {
+            Some(ref dir) => persistence::Save::load(dir).context("loading world")?,
+            None => None,
+        };
+        let recording = match cfg.record {
+            Some(ref path) => {
+                let file = fs::File::create(path)
+                    .with_context(|| format!("creating recording {}", path.display()))?;
+                info!(path = %path.display(), "recording");
+                Some(recording::Writer::new(BufWriter::new(file)).context("writing recording")?)
+            }
+            None => None,
+        };
+        let incoming = incoming
+            .inspect(|x| trace!(address = %x.remote_address(), "connection incoming"))
+            .buffer_unordered(16)
+            .boxed()
+            .fuse();
+        let (client_events_send, client_events) = mpsc::channel(128);
+        Ok(Self {
+            endpoint,
+            local_addr,
+            incoming,
+            client_events_send,
+            client_events: client_events.fuse(),
+            state: State::new(cfg, save, recording),
+        })
+    }
+
+    /// Address on which connections are accepted
+    ///
+    /// Useful when `Config::listen` specifies port 0, requesting an arbitrary free port.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Handle network events that have already arrived, then advance the simulation by one step
+    ///
+    /// An alternative to `run` for callers that control the passage of time themselves, such as
+    /// tests. Callers must yield to the runtime between steps for I/O to make progress.
+    pub fn step(&mut self) {
+        while let Some(Some(conn)) = self.incoming.next().now_or_never() {
+            self.state.on_connect(conn, self.client_events_send.clone());
+        }
+        while let Some(Some((id, event))) = self.client_events.next().now_or_never() {
+            self.state.on_client_event(id, event);
+        }
+        self.state.on_step();
+    }
+
+    /// Run in real time until `shutdown` completes, then shut down
+    pub async fn run(mut self, shutdown: impl Future<Output = ()>) {
+        let mut ticks =
+            tokio::time::interval(Duration::from_secs_f64(1.0 / self.state.cfg.rate as f64)).fuse();
+        let mut saves = tokio::time::interval(SAVE_INTERVAL).fuse();
+        let shutdown = shutdown.fuse();
+        pin_mut!(shutdown);
+        loop {
+            select! {
+                _ = ticks.next() => { self.state.on_step() }
+                _ = saves.next() => { self.state.save() }
+                conn = self.incoming.select_next_some() => { self.state.on_connect(conn, self.client_events_send.clone()); }
+                e = self.client_events.select_next_some() => { self.state.on_client_event(e.0, e.1); }
+                () = shutdown => { break; }
+            }
+        }
+        self.shutdown().await;
+    }
+
+    /// Stop accepting connections, disconnect every client once everything already queued for it
+    /// has been sent, and save the world
+    pub async fn shutdown(self) {
+        info!("shutting down");
+        let Server {
+            endpoint,
+            incoming,
+            mut state,
+            ..
+        } = self;
+        drop(incoming);
+        state.shutdown().await;
+        // Give clients a chance to learn why they were disconnected
+        endpoint.wait_idle().await;
+    }
 }
 
-struct Server {
+/// Everything about a server but its network event sources
+struct State {
     cfg: Arc<Config>,
     sim: Sim,
     clients: DenseSlotMap<ClientId, Client>,
+    recording: Option<Recording>,
 }
 
-impl Server {
-    fn new(cfg: Config) -> Self {
+type Recording = recording::Writer<BufWriter<fs::File>>;
+
+impl State {
+    fn new(cfg: Config, save: Option<persistence::Save>, recording: Option<Recording>) -> Self {
         let cfg = Arc::new(cfg);
         Self {
-            sim: Sim::new(cfg.clone()),
+            sim: Sim::new(cfg.clone(), save),
             cfg,
             clients: DenseSlotMap::default(),
+            recording,
         }
     }
 
-    async fn run(mut self, incoming: quinn::Incoming) {
-        let mut ticks =
-            tokio::time::interval(Duration::from_secs_f64(1.0 / self.cfg.rate as f64)).fuse();
-        let mut incoming = incoming
-            .inspect(|x| trace!(address = %x.remote_address(), "connection incoming"))
-            .buffer_unordered(16);
-        let (client_events_send, client_events) = mpsc::channel(128);
-        let mut client_events = client_events.fuse();
-        loop {
-            select! {
-                _ = ticks.next() => { self.on_step() }
-                conn = incoming.select_next_some() => { self.on_connect(conn, client_events_send.clone()); }
-                e = client_events.select_next_some() => { self.on_client_event(e.0, e.1); }
+    async fn shutdown(&mut self) {
+        let mut conns = Vec::with_capacity(self.clients.len());
+        let mut sends = Vec::with_capacity(self.clients.len());
+        for (_, client) in self.clients.drain() {
+            if let Some(handles) = client.handles {
+                // Dropping the handles' channels lets the send task finish
+                sends.push(handles.send_task);
             }
+            conns.push(client.conn);
+        }
+        if tokio::time::timeout(SHUTDOWN_TIMEOUT, future::join_all(sends))
+            .await
+            .is_err()
+        {
+            warn!("timed out sending final messages to clients");
+        }
+        for conn in &conns {
+            disconnect(conn, DisconnectReason::Shutdown, "");
         }
+        self.save();
     }
 
     fn on_step(&mut self) {
-        let (spawns, delta) = self.sim.step();
-        let spawns = Arc::new(spawns);
-        let delta = Arc::new(delta);
+        let changes = self.sim.step();
         let mut overran = Vec::new();
         for (client_id, client) in &mut self.clients {
             if let Some(ref mut handles) = client.handles {
-                let r1 = handles.unordered.try_send(delta.clone());
-                let r2 = if !spawns.spawns.is_empty()
-                    || !spawns.despawns.is_empty()
-                    || !spawns.nodes.is_empty()
-                {
-                    handles.ordered.try_send(spawns.clone())
-                } else {
-                    Ok(())
-                };
+                let (spawns, delta) =
+                    self.sim
+                        .updates_for(&changes, handles.character, &mut handles.baseline);
+                record(&mut self.recording, |r| {
+                    for x in &spawns {
+                        r.spawns(handles.id, x)?;
+                    }
+                    r.state_delta(handles.id, &delta)
+                });
+                let r1 = handles.unordered.try_send(delta);
+                let r2 = spawns
+                    .into_iter()
+                    .try_for_each(|x| handles.ordered.try_send(x));
                 use mpsc::error::TrySendError::Full;
                 match (r1, r2) {
                     (Err(Full(_)), _) | (_, Err(Full(_))) => {
@@ -127,55 +320,142 @@ impl Server {
         }
         for client_id in overran {
             error!("dropping slow client {:?}", client_id.0);
-            self.clients[client_id]
-                .conn
-                .close(1u32.into(), b"client reading too slowly");
+            disconnect(
+                &self.clients[client_id].conn,
+                DisconnectReason::TooSlow,
+                "client reading too slowly",
+            );
             self.cleanup_client(client_id);
         }
     }
 
+    fn save(&mut self) {
+        // Limit what's lost if we exit abruptly
+        record(&mut self.recording, |r| Ok(r.flush()?));
+        let dir = match self.cfg.save_directory {
+            Some(ref x) => x,
+            None => return,
+        };
+        match self.sim.save().store(dir) {
+            Ok(()) => debug!("saved world"),
+            Err(e) => error!("failed to save world: {:#}", e),
+        }
+    }
+
     fn on_client_event(&mut self, client_id: ClientId, event: ClientEvent) {
         let span = error_span!("client", id = ?client_id.0);
         let _guard = span.enter();
-        let client = &mut self.clients[client_id];
+        let client = match self.clients.get_mut(client_id) {
+            Some(x) => x,
+            // Events may still arrive from a client that's been dropped
+            None => return,
+        };
         match event {
+            ClientEvent::Incompatible(version) => {
+                warn!(version, "rejected incompatible client");
+                let reason = format!(
+                    "incompatible protocol version {}; server requires version {}",
+                    version,
+                    proto::PROTOCOL_VERSION
+                );
+                disconnect(&client.conn, DisconnectReason::VersionMismatch, &reason);
+                self.clients.remove(client_id);
+            }
             ClientEvent::Hello(hello) => {
                 assert!(client.handles.is_none());
-                let snapshot = Arc::new(self.sim.snapshot());
-                let (id, entity) = self.sim.spawn_character(hello);
-                let (mut ordered_send, ordered_recv) = mpsc::channel(32);
-                ordered_send.try_send(snapshot).unwrap();
+                let capabilities = hello
+                    .capabilities
+                    .intersection(proto::Capabilities::SUPPORTED);
+                let name = hello.name.clone();
+                let (id, entity) = match self.sim.spawn_character(hello) {
+                    Ok(x) => x,
+                    Err(e) => {
+                        let details = format!("{} {}", name, e);
+                        warn!("rejected: {}", details);
+                        let reason = match e {
+                            SpawnError::AlreadyConnected => DisconnectReason::AlreadyConnected,
+                            SpawnError::WrongCredential => DisconnectReason::Rejected,
+                        };
+                        disconnect(&client.conn, reason, &details);
+                        self.clients.remove(client_id);
+                        return;
+                    }
+                };
+                let (ordered_send, ordered_recv) = mpsc::channel(32);
                 let (unordered_send, unordered_recv) = mpsc::channel(32);
+                let connection = client.conn.clone();
+                let server_hello = proto::ServerHello {
+                    protocol_version: proto::PROTOCOL_VERSION,
+                    capabilities,
+                    character: id,
+                    world_seed: self.sim.seed(),
+                    rate: self.cfg.rate,
+                };
+                record(&mut self.recording, |r| r.hello(id, &name, &server_hello));
+                let send_task = tokio::spawn(async move {
+                    let result = drive_send(
+                        connection.clone(),
+                        server_hello,
+                        unordered_recv,
+                        ordered_recv,
+                    )
+                    .await;
+                    match result {
+                        // Cleanup is left to the recv task, which will observe the closure
+                        Err(ref e) if connection_lost(e) => {}
+                        Err(e) => {
+                            error!("failed to send: {:#}", e);
+                            disconnect(&connection, DisconnectReason::ProtocolError, "");
+                        }
+                        Ok(()) => {}
+                    }
+                });
                 client.handles = Some(ClientHandles {
+                    id,
                     character: entity,
+                    baseline: Baseline::default(),
                     ordered: ordered_send,
                     unordered: unordered_send,
-                });
-                let connection = client.conn.clone();
-                let server_hello = proto::ServerHello { character: id };
-                tokio::spawn(async move {
-                    // Errors will be handled by recv task
-                    let _ =
-                        drive_send(connection, server_hello, unordered_recv, ordered_recv).await;
+                    send_task,
                 });
             }
             ClientEvent::Lost(e) => {
-                error!("lost: {:#}", e);
-                client.conn.close(0u32.into(), b"");
+                if connection_lost(&e) {
+                    debug!("lost: {:#}", e);
+                } else {
+                    error!("lost: {:#}", e);
+                    disconnect(&client.conn, DisconnectReason::ProtocolError, "");
+                }
                 self.cleanup_client(client_id);
             }
             ClientEvent::Command(cmd) => {
                 if let Some(ref x) = client.handles {
+                    record(&mut self.recording, |r| r.command(x.id, &cmd));
                     if let Err(e) = self.sim.command(x.character, cmd) {
                         error!("couldn't process command: {}", e);
                     }
                 }
             }
+            ClientEvent::BlockUpdate(update) => {
+                if let Some(ref x) = client.handles {
+                    if let Err(e) = self.sim.block_update(x.character, update) {
+                        warn!("rejected block update: {:#}", e);
+                    }
+                }
+            }
+            ClientEvent::Chat(text) => {
+                if let Some(ref x) = client.handles {
+                    if let Err(e) = self.sim.chat(x.character, &text) {
+                        warn!("rejected chat message: {:#}", e);
+                    }
+                }
+            }
         }
     }
 
     fn cleanup_client(&mut self, client: ClientId) {
         if let Some(ref x) = self.clients[client].handles {
+            record(&mut self.recording, |r| r.lost(x.id));
             self.sim.destroy(x.character);
         }
         self.clients.remove(client);
@@ -183,7 +463,7 @@ impl Server {
 
     fn on_connect(
         &mut self,
-        conn: Result<quinn::NewConnection, quinn::ConnectionError>,
+        conn: IncomingConnection,
         mut send: mpsc::Sender<(ClientId, ClientEvent)>,
     ) {
         let quinn::NewConnection {
@@ -197,6 +477,13 @@ impl Server {
                 return;
             }
         };
+        if let Some(max) = self.cfg.max_clients {
+            if self.clients.len() >= max as usize {
+                info!(address = %connection.remote_address(), "refusing connection: server full");
+                disconnect(&connection, DisconnectReason::ServerFull, "");
+                return;
+            }
+        }
         let id = self.clients.insert(Client {
             conn: connection.clone(),
             handles: None,
@@ -210,8 +497,35 @@ impl Server {
     }
 }
 
+/// Write to `recording`, if any, abandoning it on failure
+fn record(recording: &mut Option<Recording>, f: impl FnOnce(&mut Recording) -> Result<()>) {
+    if let Some(ref mut x) = *recording {
+        if let Err(e) = f(x) {
+            error!(
+                "failed to write recording, so recording has stopped: {:#}",
+                e
+            );
+            *recording = None;
+        }
+    }
+}
+
+/// Close `conn`, telling the peer why
+fn disconnect(conn: &quinn::Connection, reason: DisconnectReason, details: &str) {
+    conn.close(reason.code().into(), details.as_bytes());
+}
+
+/// How long to wait for final messages to be delivered to clients when shutting down
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Largest message accepted from a client, beyond which the connection is dropped
+///
+/// Must comfortably exceed any legitimate message, including chat of `proto::MAX_CHAT_LEN` bytes.
 const MAX_CLIENT_MSG_SIZE: usize = 1 << 16;
 
+/// How often the world is saved, if a save directory is configured, and any recording flushed
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
 async fn drive_recv(
     id: ClientId,
     mut streams: quinn::IncomingUniStreams,
@@ -219,19 +533,31 @@ async fn drive_recv(
 ) -> Result<()> {
     let hello = match streams.next().await {
         None => return Ok(()),
-        Some(stream) => {
-            codec::recv_whole::<proto::ClientHello>(MAX_CLIENT_MSG_SIZE, stream?).await?
-        }
+        Some(stream) => codec::recv_whole_bytes(MAX_CLIENT_MSG_SIZE, stream?).await?,
     };
+    // Check the version first, since the rest of the hello may be unintelligible otherwise
+    let version = codec::protocol_version(&hello)?;
+    if version != proto::PROTOCOL_VERSION {
+        let _ = send.send((id, ClientEvent::Incompatible(version))).await;
+        return Ok(());
+    }
+    let hello = bincode::deserialize::<proto::ClientHello>(&hello)?;
     let _ = send.send((id, ClientEvent::Hello(hello))).await;
 
-    let mut cmds = streams
+    let mut msgs = streams
         .map(|stream| async {
-            Ok::<_, Error>(codec::recv_whole::<proto::Command>(MAX_CLIENT_MSG_SIZE, stream?).await?)
+            Ok::<_, Error>(
+                codec::recv_whole::<proto::ClientMessage>(MAX_CLIENT_MSG_SIZE, stream?).await?,
+            )
         })
         .buffer_unordered(16); // Allow a modest amount of out-of-order completion
-    while let Some(msg) = cmds.try_next().await? {
-        let _ = send.send((id, ClientEvent::Command(msg))).await;
+    while let Some(msg) = msgs.try_next().await? {
+        let event = match msg {
+            proto::ClientMessage::Command(x) => ClientEvent::Command(x),
+            proto::ClientMessage::BlockUpdate(x) => ClientEvent::BlockUpdate(x),
+            proto::ClientMessage::Chat(x) => ClientEvent::Chat(x),
+        };
+        let _ = send.send((id, event)).await;
     }
     Ok(())
 }
@@ -245,14 +571,15 @@ async fn drive_send(
     let mut stream = conn.open_uni().await?;
     codec::send(&mut stream, &hello).await?;
 
-    tokio::spawn(async move {
-        // Errors will be handled by recv task
-        let _ = drive_send_unordered(conn.clone(), unordered).await;
-    });
-
-    while let Some(msg) = ordered.next().await {
-        codec::send(&mut stream, &msg).await?;
-    }
+    let send_ordered = async {
+        while let Some(msg) = ordered.next().await {
+            codec::send(&mut stream, &msg).await?;
+        }
+        // Wait for delivery
+        stream.finish().await?;
+        Ok::<_, Error>(())
+    };
+    future::try_join(send_ordered, drive_send_unordered(conn, unordered)).await?;
 
     Ok(())
 }
@@ -279,17 +606,43 @@ struct Client {
 }
 
 struct ClientHandles {
+    id: EntityId,
     character: Entity,
+    /// What the client has been told of frequently updated state
+    baseline: Baseline,
     ordered: mpsc::Sender<Ordered>,
     unordered: mpsc::Sender<Unordered>,
+    /// Completes once the connection is lost or both channels are closed and drained
+    send_task: JoinHandle<()>,
 }
 
 enum ClientEvent {
     Hello(proto::ClientHello),
+    /// The client speaks the given, unsupported protocol version
+    Incompatible(u16),
     Command(proto::Command),
+    BlockUpdate(proto::BlockUpdate),
+    Chat(String),
     Lost(Error),
 }
 
-type Unordered = Arc<proto::StateDelta>;
+type Unordered = proto::StateDelta;
 
-type Ordered = Arc<proto::Spawns>;
+type Ordered = proto::Spawns;
+
+/// Whether `error` arose from the connection having already ended
+fn connection_lost(error: &Error) -> bool {
+    error.chain().any(|x| {
+        if x.is::<quinn::ConnectionError>() {
+            return true;
+        }
+        match x.downcast_ref::<quinn::WriteError>() {
+            Some(quinn::WriteError::ConnectionClosed(_)) => return true,
+            _ => {}
+        }
+        match x.downcast_ref::<quinn::ReadError>() {
+            Some(quinn::ReadError::ConnectionClosed(_)) => true,
+            _ => false,
+        }
+    })
+}
@@ -1,8 +1,16 @@
+use std::path::Path;
+
+use server::Config;
+
 fn main() {
     // Set up logging
     tracing_subscriber::fmt::init();
 
-    if let Err(e) = server::run() {
+    let cfg = match std::env::args_os().nth(1) {
+        Some(path) => Config::load(Path::new(&path)),
+        None => Ok(Config::default()),
+    };
+    if let Err(e) = cfg.and_then(|cfg| server::run(cfg, server::termination())) {
         eprintln!("{:#}", e);
         std::process::exit(1);
     }
@@ -0,0 +1,79 @@
+//! Storage of the world on disk
+//!
+//! Nodes are identified by their paths from the root, since `NodeId`s depend on the order in which
+//! nodes happen to be created. Anything that can be regenerated from the world's seed is omitted.
+
+use std::{
+    fs,
+    io::{self, BufReader, BufWriter, Write},
+    path::Path,
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use common::{
+    dodeca::{Side, Vertex},
+    world::Material,
+    EntityId,
+};
+
+/// Everything about a world that can't be regenerated from its seed
+#[derive(Serialize, Deserialize)]
+pub struct Save {
+    pub seed: u64,
+    /// Chunks that have been modified since they were generated
+    pub chunks: Vec<Chunk>,
+    pub characters: Vec<(EntityId, Character)>,
+}
+
+impl Save {
+    /// Read the save in `dir`, if any
+    pub fn load(dir: &Path) -> Result<Option<Self>> {
+        let file = match fs::File::open(dir.join(FILE_NAME)) {
+            Ok(x) => x,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).context("opening save"),
+        };
+        Ok(Some(
+            bincode::deserialize_from(BufReader::new(file)).context("parsing save")?,
+        ))
+    }
+
+    /// Write to `dir`, replacing any existing save
+    pub fn store(&self, dir: &Path) -> Result<()> {
+        fs::create_dir_all(dir).context("creating save directory")?;
+        // Write to a separate file first so that an interrupted write can't corrupt the save
+        let temp = dir.join(TEMP_FILE_NAME);
+        let mut file = BufWriter::new(fs::File::create(&temp).context("creating save")?);
+        bincode::serialize_into(&mut file, self).context("writing save")?;
+        file.flush().context("writing save")?;
+        file.get_ref().sync_all().context("writing save")?;
+        fs::rename(&temp, dir.join(FILE_NAME)).context("replacing save")?;
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Chunk {
+    /// Path from the root to the node the chunk belongs to
+    pub node: Vec<Side>,
+    pub vertex: Vertex,
+    /// Voxels whose materials differ from those generated
+    pub voxels: Vec<([u8; 3], Material)>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Character {
+    pub name: String,
+    /// Digest of the owner's credential
+    pub credential: [u8; 32],
+    /// Path from the root to the node the character is in
+    pub node: Vec<Side>,
+    /// Transform from the character's frame to that of `node`
+    pub local: na::Matrix4<f32>,
+    pub orientation: na::UnitQuaternion<f32>,
+}
+
+const FILE_NAME: &str = "world.bin";
+const TEMP_FILE_NAME: &str = "world.bin.tmp";
@@ -1,66 +1,238 @@
-use std::{mem, sync::Arc};
+use std::{collections::VecDeque, fmt, mem, sync::Arc};
 
-use fxhash::FxHashMap;
+use anyhow::{bail, Result};
+use fxhash::{FxHashMap, FxHashSet};
 use hecs::Entity;
 use rand::rngs::SmallRng;
 use rand::{Rng, SeedableRng};
-use tracing::{debug, error_span, info, trace};
+use tracing::{debug, error_span, info};
 
-use crate::Config;
+use crate::{persistence, Config};
 use common::{
+    collision,
     graph::{Graph, NodeId},
-    math,
-    proto::{self, ClientHello, Command, Component, FreshNode, Position, Spawns, StateDelta},
+    math, movement,
+    proto::{
+        self, BlockUpdate, ChatMessage, ClientHello, Command, Component, FreshNode, PackedPosition,
+        PackedRotation, Position, Spawns, StateDelta, MAX_CHAT_LEN,
+    },
+    world::{ChunkId, Material, VoxelData, BLOCK_REACH, SUBDIVISION_FACTOR},
+    worldgen::{ChunkParams, NodeState},
     EntityId, Step,
 };
 
 pub struct Sim {
     cfg: Arc<Config>,
     rng: SmallRng,
+    seed: u64,
     step: Step,
     entity_ids: FxHashMap<EntityId, Entity>,
     world: hecs::World,
-    graph: Graph<(), ()>,
-    spawns: Vec<Entity>,
-    despawns: Vec<EntityId>,
+    graph: Graph<NodeState, VoxelData>,
+    /// Every voxel changed since generation, for transmission to clients as they learn of chunks
+    modifications: FxHashMap<ChunkId, FxHashMap<[u8; 3], Material>>,
+    block_updates: Vec<BlockUpdate>,
+    chat: Vec<ChatMessage>,
+    /// Entities within each node as of the last step
+    occupants: FxHashMap<NodeId, Vec<(EntityId, Entity)>>,
+    /// Entities whose position or orientation may have changed since the last step
+    dirty: FxHashSet<Entity>,
+    /// Characters whose owners aren't connected
+    dormant: FxHashMap<EntityId, Dormant>,
 }
 
 impl Sim {
-    pub fn new(cfg: Arc<Config>) -> Self {
+    pub fn new(cfg: Arc<Config>, save: Option<persistence::Save>) -> Self {
+        Self::with_rng(cfg, save, SmallRng::from_entropy())
+    }
+
+    /// Like `new`, but with every random choice drawn from `rng`
+    ///
+    /// Given the same `rng` seed, configuration, save, and sequence of calls, the resulting
+    /// simulation behaves identically.
+    pub fn with_rng(cfg: Arc<Config>, save: Option<persistence::Save>, mut rng: SmallRng) -> Self {
+        let seed = match save {
+            Some(ref save) => {
+                info!(seed = save.seed, "loading world");
+                save.seed
+            }
+            None => {
+                let seed = cfg.seed.unwrap_or_else(|| rng.gen());
+                info!(seed, "generating world");
+                seed
+            }
+        };
         let mut result = Self {
             cfg,
-            rng: SmallRng::from_entropy(),
+            rng,
+            seed,
             step: 0,
             entity_ids: FxHashMap::default(),
             world: hecs::World::new(),
             graph: Graph::new(),
-            spawns: Vec::new(),
-            despawns: Vec::new(),
+            modifications: FxHashMap::default(),
+            block_updates: Vec::new(),
+            chat: Vec::new(),
+            occupants: FxHashMap::default(),
+            dirty: FxHashSet::default(),
+            dormant: FxHashMap::default(),
         };
+        *result.graph.get_mut(NodeId::ROOT) = Some(NodeState::root(seed));
+        if let Some(save) = save {
+            result.load(save);
+        }
         result
             .graph
             .ensure_nearby(NodeId::ROOT, result.cfg.view_distance);
+        result.populate_fresh_nodes();
         result
     }
 
-    pub fn spawn_character(&mut self, hello: ClientHello) -> (EntityId, Entity) {
-        let id = self.new_id();
-        info!(%id, name = %hello.name, "spawning character");
-        let position = Position {
-            node: NodeId::ROOT,
-            local: na::one(),
+    /// Restore the contents of a saved world
+    fn load(&mut self, save: persistence::Save) {
+        for chunk in save.chunks {
+            let node = self.graph.ensure_path(&chunk.node);
+            // Applied when the chunk is generated
+            self.modifications.insert(
+                ChunkId::new(node, chunk.vertex),
+                chunk.voxels.into_iter().collect(),
+            );
+        }
+        for (id, ch) in save.characters {
+            let node = self.graph.ensure_path(&ch.node);
+            self.dormant.insert(
+                id,
+                Dormant {
+                    name: ch.name,
+                    credential: ch.credential,
+                    position: Position {
+                        node,
+                        local: ch.local,
+                    },
+                    orientation: ch.orientation,
+                },
+            );
+        }
+        info!(
+            chunks = self.modifications.len(),
+            characters = self.dormant.len(),
+            "loaded world"
+        );
+    }
+
+    /// Capture everything about the world that can't be regenerated from its seed
+    pub fn save(&self) -> persistence::Save {
+        let mut characters = self
+            .dormant
+            .iter()
+            .map(|(&id, ch)| {
+                (
+                    id,
+                    persistence::Character {
+                        name: ch.name.clone(),
+                        credential: ch.credential,
+                        node: self.graph.path(ch.position.node),
+                        local: ch.position.local,
+                        orientation: ch.orientation,
+                    },
+                )
+            })
+            .collect::<Vec<_>>();
+        for (_, (&id, ch, position)) in self
+            .world
+            .query::<(&EntityId, &Character, &Position)>()
+            .iter()
+        {
+            characters.push((
+                id,
+                persistence::Character {
+                    name: ch.name.clone(),
+                    credential: ch.credential,
+                    node: self.graph.path(position.node),
+                    local: position.local,
+                    orientation: ch.orientation,
+                },
+            ));
+        }
+        persistence::Save {
+            seed: self.seed,
+            chunks: self
+                .modifications
+                .iter()
+                .map(|(chunk_id, voxels)| persistence::Chunk {
+                    node: self.graph.path(chunk_id.node),
+                    vertex: chunk_id.vertex,
+                    voxels: voxels
+                        .iter()
+                        .map(|(&coords, &material)| (coords, material))
+                        .collect(),
+                })
+                .collect(),
+            characters,
+        }
+    }
+
+    /// Seed from which world contents are generated
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Give a newly connected player control of their character, creating it if necessary
+    ///
+    /// Fails if the character is already controlled, or belongs to someone with a different
+    /// credential.
+    pub fn spawn_character(
+        &mut self,
+        hello: ClientHello,
+    ) -> Result<(EntityId, Entity), SpawnError> {
+        let credential = digest(&hello.credential);
+        if self
+            .world
+            .query::<&Character>()
+            .iter()
+            .any(|(_, ch)| ch.name == hello.name)
+        {
+            return Err(SpawnError::AlreadyConnected);
+        }
+        let existing = self
+            .dormant
+            .iter()
+            .find(|(_, ch)| ch.name == hello.name)
+            .map(|(&id, ch)| (id, ch.credential));
+        let (id, position, orientation) = match existing {
+            Some((_, x)) if x != credential => return Err(SpawnError::WrongCredential),
+            Some((id, _)) => {
+                let ch = self.dormant.remove(&id).unwrap();
+                info!(%id, name = %hello.name, "restoring character");
+                (id, ch.position, ch.orientation)
+            }
+            None => {
+                let id = self.new_id();
+                info!(%id, name = %hello.name, "spawning character");
+                let position = Position {
+                    node: NodeId::ROOT,
+                    local: na::one(),
+                };
+                (id, position, na::one())
+            }
         };
+        // Ensure the character's surroundings are ready before it's first simulated
+        self.graph
+            .ensure_nearby(position.node, self.cfg.view_distance);
+        self.populate_fresh_nodes();
         let character = Character {
             name: hello.name,
+            credential,
             latest_command: 0,
-            speed: 0.0,
-            direction: -na::Vector3::z_axis(),
-            orientation: na::one(),
-            command_node: NodeId::ROOT,
+            commands: VecDeque::new(),
+            input: movement::Input::default(),
+            motion: movement::State::default(),
+            orientation,
+            chat_allowance: CHAT_BURST,
         };
         let entity = self.world.spawn((id, position, character));
-        self.spawns.push(entity);
-        (id, entity)
+        self.entity_ids.insert(id, entity);
+        Ok((id, entity))
     }
 
     pub fn command(
@@ -69,121 +241,395 @@ impl Sim {
         command: Command,
     ) -> Result<(), hecs::ComponentError> {
         let mut ch = self.world.get_mut::<Character>(entity)?;
-        if command.step > ch.latest_command {
-            ch.latest_command = command.step;
-            let (direction, speed) = na::Unit::new_and_get(command.velocity);
-            ch.direction = if speed == 0.0 {
-                -na::Vector3::z_axis()
-            } else {
-                direction
-            };
-            ch.speed = speed.min(1.0);
-            ch.orientation = command.orientation;
-            ch.command_node = command.node;
+        let latest = ch
+            .commands
+            .back()
+            .map_or(ch.latest_command, |&(step, _)| step);
+        if command.step > latest {
+            if ch.commands.len() == MAX_QUEUED_COMMANDS {
+                // The client is running ahead of us; skip its oldest input to catch up
+                ch.commands.pop_front();
+            }
+            ch.commands
+                .push_back((command.step, movement::Input::from(&command)));
+            if ch.orientation != command.orientation {
+                ch.orientation = command.orientation;
+                self.dirty.insert(entity);
+            }
+        }
+        Ok(())
+    }
+
+    /// Apply a modification to the world requested by the owner of `entity`, if it's permitted
+    pub fn block_update(&mut self, entity: Entity, update: BlockUpdate) -> Result<()> {
+        let position = *self.world.get::<Position>(entity)?;
+        let chunk_id = update.chunk_id;
+        if !self.graph.contains(chunk_id.node)
+            || update
+                .coords
+                .iter()
+                .any(|&x| x as usize >= SUBDIVISION_FACTOR)
+        {
+            bail!("no such voxel");
+        }
+        let coords = [
+            update.coords[0] as usize,
+            update.coords[1] as usize,
+            update.coords[2] as usize,
+        ];
+        let old_material = match *self.graph.get_cube(chunk_id.node, chunk_id.vertex) {
+            None => bail!("chunk not generated"),
+            Some(ref voxels) => voxels.get(coords),
+        };
+        if (old_material == Material::Void) == (update.new_material == Material::Void) {
+            bail!("can only place into empty space or break solid blocks");
+        }
+
+        // Chunks extend at most ~2.45 units from the nodes they're enumerated from
+        let center =
+            na::Vector3::from(coords).map(|x| (x as f32 + 0.5) / SUBDIVISION_FACTOR as f32);
+        let start = position.local * math::origin();
+        let in_reach = self
+            .graph
+            .nearby_cubes(position, BLOCK_REACH + 2.5)
+            .into_iter()
+            .find(|&(node, vertex, _, _)| node == chunk_id.node && vertex == chunk_id.vertex)
+            .map_or(false, |(_, _, _, transform)| {
+                math::distance(&start, &(transform * center.push(1.0))) <= BLOCK_REACH as f32
+            });
+        if !in_reach {
+            bail!("out of reach");
+        }
+        if update.new_material != Material::Void
+            && self
+                .world
+                .query::<(&Character, &Position)>()
+                .iter()
+                .any(|(_, (_, position))| {
+                    collision::overlaps_voxel(&self.graph, position, chunk_id, coords)
+                })
+        {
+            bail!("would obstruct a character");
         }
+
+        self.graph
+            .get_cube_mut(chunk_id.node, chunk_id.vertex)
+            .as_mut()
+            .unwrap()
+            .set(coords, update.new_material);
+        self.modifications
+            .entry(chunk_id)
+            .or_default()
+            .insert(update.coords, update.new_material);
+        self.block_updates.push(update);
+        Ok(())
+    }
+
+    /// Relay a chat message from the owner of `entity` to everyone, if it's acceptable
+    pub fn chat(&mut self, entity: Entity, text: &str) -> Result<()> {
+        // Control characters could disrupt the display of the log
+        let text = text.chars().filter(|c| !c.is_control()).collect::<String>();
+        let text = text.trim();
+        if text.is_empty() {
+            bail!("message empty");
+        }
+        if text.len() > MAX_CHAT_LEN {
+            bail!("message too long");
+        }
+        let id = *self.world.get::<EntityId>(entity)?;
+        let mut ch = self.world.get_mut::<Character>(entity)?;
+        if ch.chat_allowance < 1.0 {
+            bail!("sending messages too quickly");
+        }
+        ch.chat_allowance -= 1.0;
+        self.chat.push(ChatMessage {
+            sender: id,
+            name: ch.name.clone(),
+            text: text.into(),
+        });
         Ok(())
     }
 
     pub fn destroy(&mut self, entity: Entity) {
         let id = *self.world.get::<EntityId>(entity).unwrap();
+        if let (Ok(ch), Ok(position)) = (
+            self.world.get::<Character>(entity),
+            self.world.get::<Position>(entity),
+        ) {
+            // Retain the character so that it's saved
+            self.dormant.insert(
+                id,
+                Dormant {
+                    name: ch.name.clone(),
+                    credential: ch.credential,
+                    position: *position,
+                    orientation: ch.orientation,
+                },
+            );
+        }
         self.entity_ids.remove(&id);
         self.world.despawn(entity).unwrap();
-        self.despawns.push(id);
     }
 
-    /// Collect information about all entities, for transmission to new clients
-    pub fn snapshot(&self) -> Spawns {
-        let mut spawns = Spawns {
-            step: self.step,
-            spawns: Vec::new(),
-            despawns: Vec::new(),
-            nodes: self
-                .graph
-                .tree()
-                .map(|(side, parent)| FreshNode { side, parent })
-                .collect(),
-        };
-        for (entity, &id) in &mut self.world.query::<&EntityId>() {
-            spawns.spawns.push((id, dump_entity(&self.world, entity)));
-        }
-        spawns
-    }
-
-    pub fn step(&mut self) -> (Spawns, StateDelta) {
+    pub fn step(&mut self) -> Changes {
         let span = error_span!("step", step = self.step);
         let _guard = span.enter();
 
         // Simulate
-        for (_, (&id, ch, pos)) in self
+        for (entity, (&id, ch, pos)) in self
             .world
-            .query::<(&EntityId, &Character, &mut Position)>()
+            .query::<(&EntityId, &mut Character, &mut Position)>()
             .iter()
         {
-            let next_xf =
-                pos.local * math::translate_along(&ch.direction, ch.speed / self.cfg.rate as f32);
-            pos.local = math::renormalize_isometry(&next_xf);
-            let (next_node, transition_xf) = self.graph.normalize_transform(pos.node, &pos.local);
-            if next_node != pos.node {
-                debug!(%id, node = ?next_node, "transition");
-                pos.node = next_node;
-                pos.local = transition_xf * pos.local;
-                self.graph.ensure_nearby(next_node, self.cfg.view_distance);
+            // Apply each command for exactly one step, so clients can replay them identically
+            if let Some((step, input)) = ch.commands.pop_front() {
+                ch.latest_command = step;
+                ch.input = input;
+            }
+            ch.chat_allowance =
+                (ch.chat_allowance + CHAT_RATE / self.cfg.rate as f32).min(CHAT_BURST);
+            let prev = *pos;
+            movement::step(
+                &self.graph,
+                |x| x,
+                pos,
+                &mut ch.motion,
+                &ch.input,
+                1.0 / self.cfg.rate as f32,
+            );
+            if pos.node != prev.node || pos.local != prev.local {
+                self.dirty.insert(entity);
+            }
+            if pos.node != prev.node {
+                debug!(%id, node = ?pos.node, "transition");
+                self.graph.ensure_nearby(pos.node, self.cfg.view_distance);
             }
         }
 
-        // Capture state changes for broadcast to clients
-        let mut spawns = Vec::with_capacity(self.spawns.len());
-        for entity in self.spawns.drain(..) {
-            let id = *self.world.get::<EntityId>(entity).unwrap();
-            spawns.push((id, dump_entity(&self.world, entity)));
-        }
-        if !self.graph.fresh().is_empty() {
-            trace!(count = self.graph.fresh().len(), "broadcasting fresh nodes");
+        self.populate_fresh_nodes();
+
+        self.occupants.clear();
+        for (entity, (&id, pos)) in self.world.query::<(&EntityId, &Position)>().iter() {
+            self.occupants
+                .entry(pos.node)
+                .or_default()
+                .push((id, entity));
         }
-        let spawns = Spawns {
+
+        // Capture state changes for transmission to clients
+        let mut changes = Changes {
             step: self.step,
-            spawns,
-            despawns: mem::replace(&mut self.despawns, Vec::new()),
-            nodes: self
-                .graph
-                .fresh()
-                .iter()
-                .map(|&id| {
-                    let side = self.graph.parent(id).unwrap();
-                    FreshNode {
-                        side,
-                        parent: self.graph.neighbor(id, side).unwrap(),
-                    }
-                })
-                .collect(),
+            positions: Vec::new(),
+            orientations: Vec::new(),
+            block_updates: mem::replace(&mut self.block_updates, Vec::new()),
+            chat: mem::replace(&mut self.chat, Vec::new()),
         };
-        self.graph.clear_fresh();
+        for entity in self.dirty.drain() {
+            let id = match self.world.get::<EntityId>(entity) {
+                Ok(id) => *id,
+                // Destroyed since it changed
+                Err(_) => continue,
+            };
+            if let Ok(position) = self.world.get::<Position>(entity) {
+                changes.positions.push((id, PackedPosition::new(&position)));
+            }
+            if let Ok(ch) = self.world.get::<Character>(entity) {
+                changes
+                    .orientations
+                    .push((id, PackedRotation::new(&ch.orientation)));
+            }
+        }
 
-        // TODO: Omit unchanged (e.g. freshly spawned) entities (dirty flag?)
-        let delta = StateDelta {
-            step: self.step,
-            positions: self
-                .world
-                .query::<(&EntityId, &Position)>()
-                .iter()
-                .map(|(_, (&id, &position))| (id, position))
-                .collect(),
-            character_orientations: self
-                .world
-                .query::<(&EntityId, &Character)>()
+        self.step += 1;
+        changes
+    }
+
+    /// Describe the outcome of a step to the owner of `character`
+    ///
+    /// `changes` is as returned by `step`. Only nodes and entities within view of `character` are
+    /// described, and entities are spawned and despawned as they enter and leave it. Frequently
+    /// updated state is only included where `baseline` doesn't already reflect it, so that idle
+    /// entities cost nothing.
+    ///
+    /// Returns the messages for the client's ordered stream, if any, followed by the `StateDelta`.
+    pub fn updates_for(
+        &self,
+        changes: &Changes,
+        character: Entity,
+        baseline: &mut Baseline,
+    ) -> (Vec<Spawns>, StateDelta) {
+        let ch = self.world.get::<Character>(character).unwrap();
+        let own_id = *self.world.get::<EntityId>(character).unwrap();
+        let own_position = *self.world.get::<Position>(character).unwrap();
+        let nearby = self
+            .graph
+            .nearby_nodes(own_position.node, self.cfg.view_distance);
+        let mut spawns = Spawns {
+            step: changes.step,
+            spawns: Vec::new(),
+            despawns: Vec::new(),
+            nodes: Vec::new(),
+            block_updates: changes
+                .block_updates
                 .iter()
-                .map(|(_, (&id, ch))| (id, ch.orientation))
+                .filter(|update| baseline.nodes.contains(&update.chunk_id.node))
+                .cloned()
                 .collect(),
+            chat: changes.chat.clone(),
         };
 
-        self.step += 1;
-        (spawns, delta)
+        let revealed = self
+            .graph
+            .reveal(&mut baseline.nodes, nearby.iter().cloned());
+        for &node in &revealed {
+            if let Some(side) = self.graph.parent(node) {
+                spawns.nodes.push(FreshNode {
+                    id: node,
+                    side,
+                    parent: self.graph.neighbor(node, side).unwrap(),
+                });
+            }
+            for vertex in self.graph.cubes_at(node) {
+                let chunk_id = ChunkId::new(node, vertex);
+                let modifications = match self.modifications.get(&chunk_id) {
+                    None => continue,
+                    Some(x) => x,
+                };
+                spawns.block_updates.extend(modifications.iter().map(
+                    |(&coords, &new_material)| BlockUpdate {
+                        chunk_id,
+                        coords,
+                        new_material,
+                    },
+                ));
+            }
+        }
+
+        let visible = nearby
+            .into_iter()
+            .filter_map(|node| self.occupants.get(&node))
+            .flatten()
+            .cloned()
+            .collect::<FxHashMap<_, _>>();
+        // Entities that left view, or were destroyed
+        spawns.despawns = baseline
+            .visible
+            .iter()
+            .filter(|id| !visible.contains_key(id))
+            .cloned()
+            .collect();
+        for id in &spawns.despawns {
+            baseline.visible.remove(id);
+            baseline.positions.remove(id);
+            baseline.orientations.remove(id);
+        }
+        for (&id, &entity) in &visible {
+            if !baseline.visible.insert(id) {
+                continue;
+            }
+            // Entered view; the spawn includes all current state
+            if let Ok(position) = self.world.get::<Position>(entity) {
+                baseline
+                    .positions
+                    .insert(id, PackedPosition::new(&position));
+            }
+            if let Ok(ch) = self.world.get::<Character>(entity) {
+                baseline
+                    .orientations
+                    .insert(id, PackedRotation::new(&ch.orientation));
+            }
+            spawns.spawns.push((id, dump_entity(&self.world, entity)));
+        }
+
+        let own_position = PackedPosition::new(&own_position);
+        baseline.positions.insert(own_id, own_position);
+        let mut delta = StateDelta {
+            step: changes.step,
+            latest_command: ch.latest_command,
+            motion: ch.motion,
+            positions: vec![(own_id, own_position)],
+            character_orientations: Vec::new(),
+        };
+        for &(id, position) in &changes.positions {
+            if id != own_id
+                && baseline.visible.contains(&id)
+                && baseline.positions.insert(id, position) != Some(position)
+            {
+                delta.positions.push((id, position));
+            }
+        }
+        for &(id, orientation) in &changes.orientations {
+            // Clients control their own orientation
+            if id != own_id
+                && baseline.visible.contains(&id)
+                && baseline.orientations.insert(id, orientation) != Some(orientation)
+            {
+                delta.character_orientations.push((id, orientation));
+            }
+        }
+
+        // Revealing heavily modified regions can produce more block updates than fit in one
+        // message, so spread them across as many as necessary
+        let block_updates = mem::replace(&mut spawns.block_updates, Vec::new());
+        let mut batches = block_updates.chunks(MAX_BLOCK_UPDATES_PER_MESSAGE);
+        spawns.block_updates = batches.next().map_or_else(Vec::new, |x| x.to_vec());
+        let mut messages = Vec::new();
+        if !spawns.spawns.is_empty()
+            || !spawns.despawns.is_empty()
+            || !spawns.nodes.is_empty()
+            || !spawns.block_updates.is_empty()
+            || !spawns.chat.is_empty()
+        {
+            messages.push(spawns);
+        }
+        messages.extend(batches.map(|x| Spawns {
+            step: changes.step,
+            spawns: Vec::new(),
+            despawns: Vec::new(),
+            nodes: Vec::new(),
+            block_updates: x.to_vec(),
+            chat: Vec::new(),
+        }));
+        (messages, delta)
+    }
+
+    /// Generate voxel data for nodes created since the last step
+    ///
+    /// A chunk can only be generated once every node at its corners is known, so each new node may
+    /// complete any of the chunks incident to it.
+    fn populate_fresh_nodes(&mut self) {
+        for i in 0..self.graph.fresh().len() {
+            let node = self.graph.fresh()[i];
+            *self.graph.get_mut(node) = Some(NodeState::from_parent(&self.graph, node));
+        }
+        for i in 0..self.graph.fresh().len() {
+            let cubes = self
+                .graph
+                .incident_cubes(self.graph.fresh()[i])
+                .collect::<Vec<_>>();
+            for (node, cube) in cubes {
+                if self.graph.get_cube(node, cube).is_some() {
+                    continue;
+                }
+                let chunk_id = ChunkId::new(node, cube);
+                if let Some(params) = ChunkParams::new(&self.graph, chunk_id) {
+                    let mut voxels = params.generate();
+                    if let Some(modifications) = self.modifications.get(&chunk_id) {
+                        for (&[x, y, z], &material) in modifications {
+                            voxels.set([x as usize, y as usize, z as usize], material);
+                        }
+                    }
+                    *self.graph.get_cube_mut(node, cube) = Some(voxels);
+                }
+            }
+        }
+        self.graph.clear_fresh();
     }
 
     fn new_id(&mut self) -> EntityId {
         loop {
             let id = self.rng.gen();
-            if !self.entity_ids.contains_key(&id) {
+            if !self.entity_ids.contains_key(&id) && !self.dormant.contains_key(&id) {
                 return id;
             }
         }
@@ -206,9 +652,359 @@ fn dump_entity(world: &hecs::World, entity: Entity) -> Vec<Component> {
 
 struct Character {
     name: String,
+    /// Digest of the owner's credential
+    credential: [u8; 32],
     orientation: na::UnitQuaternion<f32>,
-    direction: na::Unit<na::Vector3<f32>>,
-    speed: f32,
+    /// Input applied during the most recent step
+    input: movement::Input,
+    motion: movement::State,
+    /// Step of the command `input` came from
     latest_command: Step,
-    command_node: NodeId,
+    /// Commands received but not yet applied
+    commands: VecDeque<(Step, movement::Input)>,
+    /// Number of chat messages that may be sent immediately
+    chat_allowance: f32,
+}
+
+/// Digest of a player's credential, so that saves can't be used to impersonate players
+fn digest(credential: &[u8]) -> [u8; 32] {
+    let mut result = [0; 32];
+    result.copy_from_slice(ring::digest::digest(&ring::digest::SHA256, credential).as_ref());
+    result
+}
+
+/// Why `Sim::spawn_character` failed
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum SpawnError {
+    /// The character is controlled by another client
+    AlreadyConnected,
+    /// The character belongs to someone with a different credential
+    WrongCredential,
+}
+
+impl fmt::Display for SpawnError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(match *self {
+            SpawnError::AlreadyConnected => "already connected",
+            SpawnError::WrongCredential => "belongs to someone else",
+        })
+    }
+}
+
+/// A character whose owner isn't connected
+struct Dormant {
+    name: String,
+    credential: [u8; 32],
+    position: Position,
+    orientation: na::UnitQuaternion<f32>,
+}
+
+/// Changes made to the world during a step
+pub struct Changes {
+    step: Step,
+    positions: Vec<(EntityId, PackedPosition)>,
+    orientations: Vec<(EntityId, PackedRotation)>,
+    block_updates: Vec<BlockUpdate>,
+    chat: Vec<ChatMessage>,
+}
+
+/// The nodes and entities a particular client knows of, and the state of those entities as last
+/// described to it
+#[derive(Default)]
+pub struct Baseline {
+    nodes: FxHashSet<NodeId>,
+    visible: FxHashSet<EntityId>,
+    positions: FxHashMap<EntityId, PackedPosition>,
+    orientations: FxHashMap<EntityId, PackedRotation>,
+}
+
+/// Most block updates sent in a single `Spawns`
+///
+/// Keeps messages well within the size limit of framing on the ordered stream.
+const MAX_BLOCK_UPDATES_PER_MESSAGE: usize = 1024;
+
+/// Number of commands a character may have waiting to be applied
+///
+/// Absorbs jitter in the arrival of commands, at the cost of latency when it fills up.
+const MAX_QUEUED_COMMANDS: usize = 4;
+
+/// Chat messages per second a character may sustain
+const CHAT_RATE: f32 = 0.5;
+/// Chat messages a character may send in quick succession
+const CHAT_BURST: f32 = 5.0;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::{dodeca::Vertex, proto::MovementMode};
+
+    fn new_sim() -> Sim {
+        Sim::with_rng(
+            Arc::new(Config::default()),
+            None,
+            SmallRng::seed_from_u64(0),
+        )
+    }
+
+    fn hello(name: &str) -> ClientHello {
+        ClientHello {
+            protocol_version: proto::PROTOCOL_VERSION,
+            capabilities: proto::Capabilities::SUPPORTED,
+            name: name.into(),
+            credential: [0; 32],
+        }
+    }
+
+    /// Commands for a deterministic run: from `step` on, player `player` faces `yaw` radians from
+    /// the default orientation and requests `velocity` in `mode`, holding jump if `jump` is set
+    const SCRIPT: &[(Step, usize, f32, [f32; 3], MovementMode, bool)] = &[
+        (0, 0, 0.0, [0.0, 0.2, -1.0], MovementMode::Fly, false),
+        (0, 1, 0.0, [1.0, 0.0, 0.0], MovementMode::Walk, true),
+        (10, 1, 0.0, [1.0, 0.0, 0.0], MovementMode::Walk, false),
+        (20, 0, 1.5, [1.0, 0.0, 0.5], MovementMode::Fly, false),
+        (35, 1, -2.0, [0.0, 0.0, -1.0], MovementMode::Walk, true),
+        (40, 1, -2.0, [0.0, 0.0, -1.0], MovementMode::Walk, false),
+        (60, 0, 3.0, [0.0, -1.0, 0.0], MovementMode::Fly, false),
+        (75, 1, 0.5, [0.5, 0.0, 0.5], MovementMode::Walk, false),
+        (90, 0, 3.0, [0.0, 0.0, 0.0], MovementMode::Fly, false),
+    ];
+
+    /// Simulate `steps` steps of a fresh world in which each of `players` is connected, each
+    /// sending a command every step according to the latest applicable entry of `script`
+    ///
+    /// Returns, for every step, the simulation's state followed by the encoded `Spawns` and
+    /// `StateDelta` sent to each player.
+    fn run(
+        players: &[&str],
+        steps: Step,
+        script: &[(Step, usize, f32, [f32; 3], MovementMode, bool)],
+    ) -> Vec<Vec<u8>> {
+        let mut sim = new_sim();
+        let mut clients = players
+            .iter()
+            .map(|&name| {
+                let (_, entity) = sim.spawn_character(hello(name)).unwrap();
+                (entity, Baseline::default())
+            })
+            .collect::<Vec<_>>();
+
+        let mut output = Vec::new();
+        for step in 0..steps {
+            for (player, &(entity, _)) in clients.iter().enumerate() {
+                let entry = script
+                    .iter()
+                    .filter(|x| x.0 <= step && x.1 == player)
+                    .last();
+                let (yaw, velocity, mode, jump) = match entry {
+                    Some(&(_, _, yaw, velocity, mode, jump)) => (yaw, velocity, mode, jump),
+                    None => continue,
+                };
+                let command = Command {
+                    step: step + 1,
+                    node: NodeId::ROOT,
+                    orientation: na::UnitQuaternion::from_axis_angle(&na::Vector3::y_axis(), yaw),
+                    velocity: velocity.into(),
+                    mode,
+                    jump,
+                    crouch: false,
+                };
+                sim.command(entity, command).unwrap();
+            }
+            let changes = sim.step();
+            let mut encoded = state(&sim);
+            for &mut (entity, ref mut baseline) in &mut clients {
+                let (spawns, delta) = sim.updates_for(&changes, entity, baseline);
+                bincode::serialize_into(&mut encoded, &spawns).unwrap();
+                bincode::serialize_into(&mut encoded, &delta).unwrap();
+            }
+            output.push(encoded);
+        }
+        output
+    }
+
+    /// Encode everything about `sim` that the commands it receives can affect
+    fn state(sim: &Sim) -> Vec<u8> {
+        let mut characters = sim
+            .world
+            .query::<(&EntityId, &Position, &Character)>()
+            .iter()
+            .map(|(_, (&id, &position, ch))| {
+                (
+                    id,
+                    position,
+                    ch.orientation,
+                    ch.motion,
+                    ch.latest_command,
+                    ch.commands.len(),
+                )
+            })
+            .collect::<Vec<_>>();
+        characters.sort_by_key(|x| x.0);
+        let mut modifications = sim
+            .modifications
+            .iter()
+            .flat_map(|(&chunk, voxels)| {
+                voxels
+                    .iter()
+                    .map(move |(&coords, &material)| (chunk, coords, material))
+            })
+            .collect::<Vec<_>>();
+        // Storage order is arbitrary
+        modifications.sort_by_cached_key(|x| bincode::serialize(x).unwrap());
+        bincode::serialize(&(sim.step, characters, modifications)).unwrap()
+    }
+
+    const STEPS: Step = 100;
+
+    #[test]
+    fn deterministic() {
+        let players = ["a", "b"];
+        let first = run(&players, STEPS, SCRIPT);
+        let second = run(&players, STEPS, SCRIPT);
+        for (step, (a, b)) in first.iter().zip(&second).enumerate() {
+            assert!(a == b, "identical input diverged at step {}", step);
+        }
+        // Make sure the commands actually had an effect
+        assert!(first.last() != run(&players, STEPS, &[]).last());
+    }
+
+    #[test]
+    fn block_update_validation() {
+        let mut sim = new_sim();
+        let (_, entity) = sim.spawn_character(hello("a")).unwrap();
+        // Characters begin at the center of the root node, at the corner of each incident chunk
+        let chunk_id = ChunkId::new(NodeId::ROOT, Vertex::A);
+        let mut voxels = VoxelData::Empty;
+        voxels.set([4, 4, 4], Material::Stone);
+        *sim.graph.get_cube_mut(NodeId::ROOT, Vertex::A) = Some(voxels);
+        *sim.graph.get_cube_mut(NodeId::ROOT, Vertex::B) = None;
+        let update = |chunk_id, coords, new_material| BlockUpdate {
+            chunk_id,
+            coords,
+            new_material,
+        };
+        let mut check = |update, expected: Option<&str>| {
+            let result = sim.block_update(entity, update);
+            assert_eq!(
+                result.err().map(|e| e.to_string()).as_deref(),
+                expected,
+                "{:?}",
+                update
+            );
+        };
+
+        check(update(chunk_id, [3, 3, 3], Material::Dirt), None);
+        check(
+            update(chunk_id, [3, 3, 3], Material::Dirt),
+            Some("can only place into empty space or break solid blocks"),
+        );
+        check(update(chunk_id, [4, 4, 4], Material::Void), None);
+        check(
+            update(chunk_id, [4, 4, 4], Material::Void),
+            Some("can only place into empty space or break solid blocks"),
+        );
+        check(
+            update(chunk_id, [11, 11, 11], Material::Dirt),
+            Some("out of reach"),
+        );
+        check(
+            update(chunk_id, [0, 0, 0], Material::Dirt),
+            Some("would obstruct a character"),
+        );
+        check(
+            update(chunk_id, [SUBDIVISION_FACTOR as u8, 0, 0], Material::Dirt),
+            Some("no such voxel"),
+        );
+        check(
+            update(
+                ChunkId::new(NodeId::ROOT, Vertex::B),
+                [3, 3, 3],
+                Material::Dirt,
+            ),
+            Some("chunk not generated"),
+        );
+
+        // Only the accepted updates took effect
+        let changes = sim.step();
+        assert_eq!(changes.block_updates.len(), 2);
+        let voxels = sim
+            .graph
+            .get_cube(NodeId::ROOT, Vertex::A)
+            .as_ref()
+            .unwrap();
+        assert_eq!(voxels.get([3, 3, 3]), Material::Dirt);
+        assert_eq!(voxels.get([4, 4, 4]), Material::Void);
+        assert_eq!(voxels.get([0, 0, 0]), Material::Void);
+    }
+
+    #[test]
+    fn large_reveals_are_split() {
+        let mut sim = new_sim();
+        let (_, entity) = sim.spawn_character(hello("a")).unwrap();
+        let n = SUBDIVISION_FACTOR as u8;
+        let modifications = sim
+            .modifications
+            .entry(ChunkId::new(NodeId::ROOT, Vertex::A))
+            .or_default();
+        for x in 0..n {
+            for y in 0..n {
+                for z in 0..n {
+                    modifications.insert([x, y, z], Material::Dirt);
+                }
+            }
+        }
+        let total = modifications.len();
+        assert!(total > MAX_BLOCK_UPDATES_PER_MESSAGE);
+
+        let changes = sim.step();
+        let (messages, _) = sim.updates_for(&changes, entity, &mut Baseline::default());
+        assert!(messages
+            .iter()
+            .all(|x| x.block_updates.len() <= MAX_BLOCK_UPDATES_PER_MESSAGE));
+        assert_eq!(
+            messages
+                .iter()
+                .map(|x| x.block_updates.len())
+                .sum::<usize>(),
+            total
+        );
+    }
+
+    #[test]
+    fn chat_limits() {
+        let mut sim = new_sim();
+        let (id, entity) = sim.spawn_character(hello("a")).unwrap();
+        let check = |result: Result<()>, expected| {
+            assert_eq!(result.unwrap_err().to_string(), expected);
+        };
+        check(
+            sim.chat(entity, &"x".repeat(MAX_CHAT_LEN + 1)),
+            "message too long",
+        );
+        check(sim.chat(entity, " \n\t"), "message empty");
+        check(sim.chat(entity, "\u{1b}\u{7}\r"), "message empty");
+        check(sim.chat(entity, "\u{1b} \u{7}"), "message empty");
+        // Limits apply to what's left after stripping control characters
+        let padded = format!("{}{}", "x".repeat(MAX_CHAT_LEN), "\u{7}".repeat(8));
+        sim.chat(entity, &padded).unwrap();
+        for _ in 1..CHAT_BURST as usize {
+            sim.chat(entity, " hi\u{7}").unwrap();
+        }
+        assert!(sim.chat(entity, "hi").is_err());
+
+        let changes = sim.step();
+        assert_eq!(changes.chat.len(), CHAT_BURST as usize);
+        assert_eq!(changes.chat[0].sender, id);
+        assert_eq!(changes.chat[0].name, "a");
+        assert_eq!(changes.chat[0].text, "x".repeat(MAX_CHAT_LEN));
+        assert_eq!(changes.chat[1].text, "hi");
+        assert!(sim.step().chat.is_empty());
+
+        // Wait long enough to regain the allowance for one more message
+        for _ in 0..(sim.cfg.rate as f32 / CHAT_RATE) as usize {
+            sim.step();
+        }
+        sim.chat(entity, "hi").unwrap();
+        assert!(sim.chat(entity, "hi").is_err());
+    }
 }
@@ -0,0 +1,140 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::Error;
+use futures::{future, FutureExt, StreamExt, TryStreamExt};
+use tokio::sync::oneshot;
+
+use common::{
+    codec,
+    proto::{self, DisconnectReason},
+    session::{self, Disconnect, Session},
+};
+use server::{Config, Server};
+
+#[tokio::test]
+async fn clients_learn_of_shutdown() {
+    let dir = save_directory("clients_learn_of_shutdown");
+    let server = Server::new(config(&dir)).unwrap();
+    let address = server.local_addr().to_string();
+    let (shutdown_send, shutdown_recv) = oneshot::channel();
+    let shutdown = async {
+        let _ = shutdown_recv.await;
+    };
+
+    let client = async {
+        let Session {
+            endpoint: _endpoint,
+            connection: _connection,
+            mut uni_streams,
+            mut ordered,
+            ..
+        } = session::connect(&address, "localhost", client_config(), &hello())
+            .await
+            .unwrap();
+        shutdown_send.send(()).unwrap();
+        // Like a real client, read both kinds of stream until the connection is lost
+        let read_ordered = async {
+            while codec::recv::<proto::Spawns>(&mut ordered).await?.is_some() {}
+            Ok::<_, Error>(())
+        };
+        let read_unordered = async {
+            while let Some(stream) = uni_streams.next().await {
+                stream?;
+            }
+            Ok::<_, Error>(())
+        };
+        let error = future::try_join(read_ordered, read_unordered)
+            .await
+            .unwrap_err();
+        let disconnect = Disconnect::new(error);
+        assert_eq!(disconnect.reason(), Some(DisconnectReason::Shutdown));
+    };
+
+    futures::join!(server.run(shutdown), client);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn shutdown_saves() {
+    let dir = save_directory("shutdown_saves");
+    let mut server = Server::new(config(&dir)).unwrap();
+    let address = server.local_addr();
+    assert_ne!(address.port(), 0);
+
+    let mut client = tokio::spawn(async move {
+        session::connect(&address.to_string(), "localhost", client_config(), &hello()).await
+    });
+    let session = loop {
+        server.step();
+        // Let I/O make progress
+        tokio::time::delay_for(Duration::from_millis(10)).await;
+        if let Some(x) = (&mut client).now_or_never() {
+            break x.unwrap().unwrap();
+        }
+    };
+    assert!(!dir.join("world.bin").exists());
+
+    server.shutdown().await;
+    assert!(dir.join("world.bin").exists());
+    let error = session
+        .uni_streams
+        .map(|x| x.map(|_| ()))
+        .try_collect::<()>();
+    let disconnect = Disconnect::new(error.await.unwrap_err().into());
+    assert_eq!(disconnect.reason(), Some(DisconnectReason::Shutdown));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// A fresh directory for a test's server to save to
+fn save_directory(test: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("hypermine-{}-{}", std::process::id(), test));
+    let _ = std::fs::remove_dir_all(&dir);
+    dir
+}
+
+fn config(save_directory: &Path) -> Config {
+    Config {
+        server_name: Some("localhost".into()),
+        // Let the OS pick a free port
+        listen: "127.0.0.1:0".parse().unwrap(),
+        save_directory: Some(save_directory.into()),
+        ..Config::default()
+    }
+}
+
+fn client_config() -> quinn::ClientConfig {
+    let mut client_cfg = quinn::ClientConfig::default();
+    Arc::get_mut(&mut client_cfg.crypto)
+        .unwrap()
+        .dangerous()
+        .set_certificate_verifier(Arc::new(AcceptAnyCert));
+    client_cfg
+}
+
+/// Accepts every certificate, since test servers' certificates are self-signed
+struct AcceptAnyCert;
+
+impl rustls::ServerCertVerifier for AcceptAnyCert {
+    fn verify_server_cert(
+        &self,
+        _roots: &rustls::RootCertStore,
+        _presented_certs: &[rustls::Certificate],
+        _dns_name: webpki::DNSNameRef,
+        _ocsp_response: &[u8],
+    ) -> Result<rustls::ServerCertVerified, rustls::TLSError> {
+        Ok(rustls::ServerCertVerified::assertion())
+    }
+}
+
+fn hello() -> proto::ClientHello {
+    proto::ClientHello {
+        protocol_version: proto::PROTOCOL_VERSION,
+        capabilities: proto::Capabilities::SUPPORTED,
+        name: "tester".into(),
+        credential: [0; 32],
+    }
+}